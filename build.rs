@@ -1,4 +1,10 @@
+use std::env;
+
 fn main() {
+    // src/jpeg.rs is used instead.
+    if env::var_os("CARGO_FEATURE_RUST_JPEG").is_some() {
+        return;
+    }
     cc::Build::new()
         .file("src/jpeg_compressor.c")
         .compile("jpeg_compressor");
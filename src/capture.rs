@@ -0,0 +1,606 @@
+#[cfg(feature = "scrap")]
+use scrap;
+use std::*;
+
+/// Byte order of a 32 bit pixel in memory.  Encoders expect `Bgrx`, i.e. 0x00RRGGBB in native u32.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    Bgrx,
+    Rgbx,
+}
+
+pub enum Damage<'a> {
+    /// the whole frame has to be compared.
+    Unknown,
+    /// only these areas may have changed, but not necessarily all of their pixels.
+    Hints(&'a [(usize, usize, usize, usize)]),
+    /// these areas have changed and are encoded without comparison.
+    Exact(&'a [(usize, usize, usize, usize)]),
+}
+
+pub struct Frame<'a> {
+    pub data: &'a [u32],
+    pub width: usize,
+    pub height: usize,
+    /// in pixels.
+    pub stride: usize,
+    pub format: PixelFormat,
+    pub timestamp: time::Instant,
+    /// changed areas since the previous frame.
+    pub damage: Damage<'a>,
+}
+
+/// A capture backend.  `frame()` may block for a while, and returns `WouldBlock` if no new frame is available yet;
+/// the caller simply retries.
+pub trait Source {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    fn frame(&mut self) -> io::Result<Frame<'_>>;
+
+    /// The layout of the monitors in the frame, which is reported to the client by ExtendedDesktopSize.
+    fn screens(&self) -> Vec<Screen> {
+        vec![Screen {
+            id: 0,
+            x: 0,
+            y: 0,
+            w: self.width(),
+            h: self.height(),
+        }]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Screen {
+    pub id: u32,
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
+impl<'a> Frame<'a> {
+    /// Converts the frame into `Bgrx` if necessary, using `buf` as a storage.
+    pub fn to_bgrx(self, buf: &'a mut Vec<u32>) -> Frame<'a> {
+        match self.format {
+            PixelFormat::Bgrx => self,
+            PixelFormat::Rgbx => {
+                buf.clear();
+                buf.extend(
+                    self.data
+                        .iter()
+                        .map(|&p| (p & 0x0000ff00) | (p & 0xff) << 16 | (p >> 16) & 0xff),
+                );
+                Frame {
+                    data: buf,
+                    format: PixelFormat::Bgrx,
+                    ..self
+                }
+            }
+        }
+    }
+}
+
+impl<S: Source + ?Sized> Source for Box<S> {
+    fn width(&self) -> usize {
+        (**self).width()
+    }
+
+    fn height(&self) -> usize {
+        (**self).height()
+    }
+
+    fn frame(&mut self) -> io::Result<Frame<'_>> {
+        (**self).frame()
+    }
+
+    fn screens(&self) -> Vec<Screen> {
+        (**self).screens()
+    }
+}
+
+/// Shares a fixed rectangle of another source.
+pub struct Crop<S: Source> {
+    source: S,
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+    damage: Vec<(usize, usize, usize, usize)>,
+}
+
+impl<S: Source> Crop<S> {
+    pub fn new(source: S, x: usize, y: usize, w: usize, h: usize) -> Self {
+        let x = cmp::min(x, source.width());
+        let y = cmp::min(y, source.height());
+        let w = cmp::min(w, source.width() - x);
+        let h = cmp::min(h, source.height() - y);
+        Crop {
+            source,
+            x,
+            y,
+            w,
+            h,
+            damage: Vec::new(),
+        }
+    }
+}
+
+impl<S: Source> Source for Crop<S> {
+    fn width(&self) -> usize {
+        self.w
+    }
+
+    fn height(&self) -> usize {
+        self.h
+    }
+
+    fn screens(&self) -> Vec<Screen> {
+        let screens: Vec<_> = self
+            .source
+            .screens()
+            .into_iter()
+            .filter_map(|s| {
+                let x0 = cmp::max(s.x, self.x);
+                let y0 = cmp::max(s.y, self.y);
+                let x1 = cmp::min(s.x + s.w, self.x + self.w);
+                let y1 = cmp::min(s.y + s.h, self.y + self.h);
+                if x0 < x1 && y0 < y1 {
+                    Some(Screen {
+                        id: s.id,
+                        x: x0 - self.x,
+                        y: y0 - self.y,
+                        w: x1 - x0,
+                        h: y1 - y0,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        if screens.is_empty() {
+            vec![Screen {
+                id: 0,
+                x: 0,
+                y: 0,
+                w: self.w,
+                h: self.h,
+            }]
+        } else {
+            screens
+        }
+    }
+
+    fn frame(&mut self) -> io::Result<Frame<'_>> {
+        let Crop {
+            source,
+            x,
+            y,
+            w,
+            h,
+            damage,
+        } = self;
+        let (x, y) = (*x, *y);
+        let frame = source.frame()?;
+        if frame.width < x + *w || frame.height < y + *h {
+            return Err(io::Error::new(io::ErrorKind::Other, "frame size"));
+        }
+
+        let mut clip = |rects: &[(usize, usize, usize, usize)]| {
+            damage.clear();
+            for &(x0, y0, x1, y1) in rects.iter() {
+                let x0 = cmp::max(x0, x) - x;
+                let y0 = cmp::max(y0, y) - y;
+                let x1 = cmp::min(cmp::max(x1, x) - x, *w);
+                let y1 = cmp::min(cmp::max(y1, y) - y, *h);
+                if x0 < x1 && y0 < y1 {
+                    damage.push((x0, y0, x1, y1));
+                }
+            }
+        };
+        let cropped = match frame.damage {
+            Damage::Unknown => Damage::Unknown,
+            Damage::Hints(rects) => {
+                clip(rects);
+                Damage::Hints(damage)
+            }
+            Damage::Exact(rects) => {
+                clip(rects);
+                Damage::Exact(damage)
+            }
+        };
+
+        Ok(Frame {
+            data: &frame.data[frame.stride * y + x..],
+            width: *w,
+            height: *h,
+            damage: cropped,
+            ..frame
+        })
+    }
+}
+
+/// Stitches several sources, e.g. monitors, into one virtual desktop.  Each source becomes a screen of
+/// ExtendedDesktopSize.
+pub struct Stitch {
+    sources: Vec<(Box<dyn Source>, usize, usize)>,
+    w: usize,
+    h: usize,
+    screen: Vec<u32>,
+    damage: Vec<(usize, usize, usize, usize)>,
+}
+
+impl Stitch {
+    const MAX_DAMAGE: usize = 64;
+
+    /// `sources` are placed at the given positions.
+    pub fn new(sources: Vec<(Box<dyn Source>, usize, usize)>) -> Self {
+        let w = sources.iter().map(|(s, x, _)| x + s.width()).max().unwrap_or(0);
+        let h = sources.iter().map(|(s, _, y)| y + s.height()).max().unwrap_or(0);
+        Stitch {
+            sources,
+            w,
+            h,
+            screen: vec![0; w * h],
+            damage: Vec::new(),
+        }
+    }
+
+    /// Places `sources` from left to right.
+    pub fn horizontal(sources: Vec<Box<dyn Source>>) -> Self {
+        let mut x = 0;
+        let sources = sources
+            .into_iter()
+            .map(|s| {
+                let sx = x;
+                x += s.width();
+                (s, sx, 0)
+            })
+            .collect();
+        Self::new(sources)
+    }
+}
+
+impl Source for Stitch {
+    fn width(&self) -> usize {
+        self.w
+    }
+
+    fn height(&self) -> usize {
+        self.h
+    }
+
+    fn screens(&self) -> Vec<Screen> {
+        self.sources
+            .iter()
+            .enumerate()
+            .map(|(i, (s, x, y))| Screen {
+                id: i as u32,
+                x: *x,
+                y: *y,
+                w: s.width(),
+                h: s.height(),
+            })
+            .collect()
+    }
+
+    fn frame(&mut self) -> io::Result<Frame<'_>> {
+        self.damage.clear();
+        let mut converted = Vec::new();
+        for (source, sx, sy) in self.sources.iter_mut() {
+            let (sx, sy) = (*sx, *sy);
+            let frame = match source.frame() {
+                Ok(frame) => frame.to_bgrx(&mut converted),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            };
+            if sx + frame.width > self.w || sy + frame.height > self.h {
+                return Err(io::Error::new(io::ErrorKind::Other, "frame size"));
+            }
+
+            // copy only the areas which may have changed; they are passed on as hints.
+            let whole = [(0, 0, frame.width, frame.height)];
+            let rects = match frame.damage {
+                Damage::Unknown => &whole[..],
+                Damage::Hints(rects) | Damage::Exact(rects) => rects,
+            };
+            for &(x0, y0, x1, y1) in rects.iter() {
+                for y in y0..y1 {
+                    let src = &frame.data[frame.stride * y..];
+                    let dst = &mut self.screen[self.w * (sy + y) + sx..];
+                    dst[x0..x1].copy_from_slice(&src[x0..x1]);
+                }
+                self.damage.push((sx + x0, sy + y0, sx + x1, sy + y1));
+            }
+        }
+        if self.damage.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        limit_damage(&mut self.damage, Self::MAX_DAMAGE);
+
+        Ok(Frame {
+            data: &self.screen,
+            width: self.w,
+            height: self.h,
+            stride: self.w,
+            format: PixelFormat::Bgrx,
+            timestamp: time::Instant::now(),
+            damage: Damage::Hints(&self.damage),
+        })
+    }
+}
+
+/// Merges the damage into the bounding box if there are too many of them.
+pub fn limit_damage(damage: &mut Vec<(usize, usize, usize, usize)>, max: usize) {
+    if damage.len() > max {
+        let b = damage.iter().fold((usize::MAX, usize::MAX, 0, 0), |b, r| {
+            (
+                cmp::min(b.0, r.0),
+                cmp::min(b.1, r.1),
+                cmp::max(b.2, r.2),
+                cmp::max(b.3, r.3),
+            )
+        });
+        damage.clear();
+        damage.push(b);
+    }
+}
+
+#[cfg(feature = "scrap")]
+pub struct ScrapSource {
+    capturer: scrap::Capturer,
+}
+
+#[cfg(feature = "scrap")]
+impl ScrapSource {
+    const INTERVAL: time::Duration = time::Duration::from_nanos(1_000_000_000 / 120);
+
+    pub fn primary() -> io::Result<Self> {
+        Ok(ScrapSource {
+            capturer: scrap::Capturer::new(scrap::Display::primary()?)?,
+        })
+    }
+
+    /// The `index`-th display in the order of `scrap::Display::all()`.
+    pub fn display(index: usize) -> io::Result<Self> {
+        let display = scrap::Display::all()?
+            .into_iter()
+            .nth(index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "display"))?;
+        Ok(ScrapSource {
+            capturer: scrap::Capturer::new(display)?,
+        })
+    }
+
+    /// All displays stitched from left to right.
+    pub fn all() -> io::Result<Stitch> {
+        let n = scrap::Display::all()?.len();
+        let sources = (0..n)
+            .map(|i| Ok(Box::new(Self::display(i)?) as Box<dyn Source>))
+            .collect::<io::Result<_>>()?;
+        Ok(Stitch::horizontal(sources))
+    }
+}
+
+#[cfg(feature = "scrap")]
+impl Source for ScrapSource {
+    fn width(&self) -> usize {
+        self.capturer.width()
+    }
+
+    fn height(&self) -> usize {
+        self.capturer.height()
+    }
+
+    fn frame(&mut self) -> io::Result<Frame<'_>> {
+        let w = self.capturer.width();
+        let h = self.capturer.height();
+        let frame = match self.capturer.frame() {
+            Ok(frame) => frame,
+            Err(err) => {
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    thread::sleep(Self::INTERVAL);
+                }
+                return Err(err);
+            }
+        };
+        let data = unsafe { slice::from_raw_parts(frame.as_ptr() as *const u32, frame.len() / 4) };
+        Ok(Frame {
+            data,
+            width: w,
+            height: h,
+            stride: data.len() / h,
+            format: PixelFormat::Bgrx,
+            timestamp: time::Instant::now(),
+            damage: Damage::Unknown,
+        })
+    }
+}
+
+#[cfg(feature = "scrap")]
+pub fn default_source() -> io::Result<Box<dyn Source>> {
+    Ok(Box::new(ScrapSource::primary()?))
+}
+
+#[cfg(not(feature = "scrap"))]
+pub fn default_source() -> io::Result<Box<dyn Source>> {
+    Err(io::Error::new(io::ErrorKind::Other, "no capture source"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum Step {
+        Busy,
+        Unknown,
+        Exact(Vec<(usize, usize, usize, usize)>),
+    }
+
+    // a w x h source of the pixels `base + i`, which returns `steps` in order.
+    struct Scripted {
+        w: usize,
+        h: usize,
+        data: Vec<u32>,
+        format: PixelFormat,
+        steps: collections::VecDeque<Step>,
+        damage: Vec<(usize, usize, usize, usize)>,
+    }
+
+    impl Scripted {
+        fn new(w: usize, h: usize, base: u32, format: PixelFormat, steps: Vec<Step>) -> Self {
+            Scripted {
+                w,
+                h,
+                data: (0..(w * h) as u32).map(|i| base + i).collect(),
+                format,
+                steps: steps.into(),
+                damage: Vec::new(),
+            }
+        }
+    }
+
+    impl Source for Scripted {
+        fn width(&self) -> usize {
+            self.w
+        }
+
+        fn height(&self) -> usize {
+            self.h
+        }
+
+        fn frame(&mut self) -> io::Result<Frame<'_>> {
+            let damage = match self.steps.pop_front() {
+                None | Some(Step::Busy) => return Err(io::ErrorKind::WouldBlock.into()),
+                Some(Step::Unknown) => Damage::Unknown,
+                Some(Step::Exact(rects)) => {
+                    self.damage = rects;
+                    Damage::Exact(&self.damage)
+                }
+            };
+            Ok(Frame {
+                data: &self.data,
+                width: self.w,
+                height: self.h,
+                stride: self.w,
+                format: self.format,
+                timestamp: time::Instant::now(),
+                damage,
+            })
+        }
+    }
+
+    fn rects(damage: &Damage) -> Option<Vec<(usize, usize, usize, usize)>> {
+        match *damage {
+            Damage::Unknown => None,
+            Damage::Hints(rects) | Damage::Exact(rects) => Some(rects.to_vec()),
+        }
+    }
+
+    #[test]
+    fn crop() {
+        let steps = vec![
+            Step::Exact(vec![(0, 0, 7, 3), (7, 5, 8, 6), (5, 3, 6, 4)]),
+            Step::Unknown,
+        ];
+        let source = Scripted::new(8, 6, 0, PixelFormat::Bgrx, steps);
+        let mut crop = Crop::new(source, 4, 2, 3, 3);
+        assert_eq!((crop.width(), crop.height()), (3, 3));
+        assert_eq!(
+            crop.screens(),
+            &[Screen {
+                id: 0,
+                x: 0,
+                y: 0,
+                w: 3,
+                h: 3
+            }]
+        );
+
+        // the damage is clipped and moved to the origin, and the pixels keep the stride of the source.
+        {
+            let frame = crop.frame().unwrap();
+            assert!(matches!(frame.damage, Damage::Exact(_)));
+            assert_eq!(rects(&frame.damage), Some(vec![(0, 0, 3, 1), (1, 1, 2, 2)]));
+            assert_eq!((frame.width, frame.height, frame.stride), (3, 3, 8));
+            assert_eq!(frame.data[0], 8 * 2 + 4);
+            assert_eq!(frame.data[8 * 2 + 2], 8 * 4 + 6);
+        }
+        assert_eq!(rects(&crop.frame().unwrap().damage), None);
+        assert_eq!(crop.frame().err().map(|e| e.kind()), Some(io::ErrorKind::WouldBlock));
+
+        // clamped to the source.
+        let crop = Crop::new(Scripted::new(8, 6, 0, PixelFormat::Bgrx, Vec::new()), 6, 2, 10, 10);
+        assert_eq!((crop.width(), crop.height()), (2, 4));
+    }
+
+    #[test]
+    fn stitch() {
+        let a = Scripted::new(4, 2, 0x100, PixelFormat::Bgrx, vec![Step::Unknown, Step::Busy]);
+        let b_steps = vec![Step::Exact(vec![(1, 1, 2, 2)]), Step::Exact(vec![(0, 0, 1, 1)])];
+        let b = Scripted::new(3, 3, 0x332211, PixelFormat::Rgbx, b_steps);
+        let mut stitch = Stitch::horizontal(vec![Box::new(a), Box::new(b)]);
+        assert_eq!((stitch.width(), stitch.height()), (7, 3));
+        assert_eq!(
+            stitch.screens(),
+            &[
+                Screen {
+                    id: 0,
+                    x: 0,
+                    y: 0,
+                    w: 4,
+                    h: 2
+                },
+                Screen {
+                    id: 1,
+                    x: 4,
+                    y: 0,
+                    w: 3,
+                    h: 3
+                }
+            ]
+        );
+
+        // the whole of the first source and the damage of the second, converted to Bgrx, are copied in place.
+        {
+            let frame = stitch.frame().unwrap();
+            assert!(matches!(frame.damage, Damage::Hints(_)));
+            assert_eq!(rects(&frame.damage), Some(vec![(0, 0, 4, 2), (5, 1, 6, 2)]));
+            assert_eq!(frame.format, PixelFormat::Bgrx);
+            assert_eq!(frame.data[7 + 3], 0x100 + 4 + 3);
+            assert_eq!(frame.data[7 + 5], 0x112233 + (3 + 1 << 16));
+            assert_eq!(frame.data[4], 0);
+        }
+
+        // a source without a new frame is skipped.
+        {
+            let frame = stitch.frame().unwrap();
+            assert_eq!(rects(&frame.damage), Some(vec![(4, 0, 5, 1)]));
+            assert_eq!(frame.data[4], 0x112233);
+        }
+        assert_eq!(stitch.frame().err().map(|e| e.kind()), Some(io::ErrorKind::WouldBlock));
+
+        // the screens of the stitched sources, cropped.
+        let a = Scripted::new(4, 2, 0, PixelFormat::Bgrx, Vec::new());
+        let b = Scripted::new(4, 2, 0, PixelFormat::Bgrx, Vec::new());
+        let crop = Crop::new(Stitch::horizontal(vec![Box::new(a), Box::new(b)]), 2, 0, 4, 2);
+        assert_eq!(
+            crop.screens(),
+            &[
+                Screen {
+                    id: 0,
+                    x: 0,
+                    y: 0,
+                    w: 2,
+                    h: 2
+                },
+                Screen {
+                    id: 1,
+                    x: 2,
+                    y: 0,
+                    w: 2,
+                    h: 2
+                }
+            ]
+        );
+    }
+}
@@ -190,9 +190,8 @@ impl QuadtreeComparator {
                     let a_area = (a.2 - a.0) * (a.3 - a.1);
                     let b_area = (b.2 - b.0) * (b.3 - b.1);
                     let m_area = (x1 - x0) * (y1 - y0);
-                    if x1 - x0 <= 2048 && m_area <= (2 << 22) / 3 && // The approx limits of Tight encoding.
-					   (m_area <= a_area + b_area + Self::TPIXELS || 15 * m_area <= 16 * (a_area + b_area))
-                    {
+                    // the limits of the encoder are applied by the server.
+                    if m_area <= a_area + b_area + Self::TPIXELS || 15 * m_area <= 16 * (a_area + b_area) {
                         Some((x0, y0, x1, y1))
                     } else if a_area < b_area {
                         callback(a.0, a.1, a.2, a.3);
@@ -1,11 +1,380 @@
+#[cfg(feature = "rust-jpeg")]
+use crate::jpeg::JpegCompressor;
 use miniz_oxide::deflate;
 use packed_simd::{i16x4, i32x4, i8x4, shuffle, u8x4, FromBits, FromCast};
 use rand;
 use std::*;
 
+pub const TIGHT: i32 = 7;
+pub const TIGHT_PNG: i32 = -260;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Subsampling {
+    S444,
+    S422,
+    S420,
+    Gray,
+}
+
+/// Per-session options which the client requests by pseudo-encodings and SetPixelFormat.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Options {
+    /// zlib level, 0-9.
+    pub compress_level: u8,
+    /// JPEG quality, 1-100, or `None` if the client has not asked for it.
+    pub quality: Option<u8>,
+    pub subsampling: Option<Subsampling>,
+    pub format: PixelFormat,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            compress_level: 1,
+            quality: None,
+            subsampling: None,
+            format: PixelFormat::SERVER,
+        }
+    }
+}
+
+impl Options {
+    const COMPRESS_LEVEL_0: i32 = -256;
+    const QUALITY_LEVEL_0: i32 = -32;
+    const FINE_QUALITY_LEVEL_0: i32 = -512;
+    const SUBSAMP_1X: i32 = -768;
+    const SUBSAMP_4X: i32 = -767;
+    const SUBSAMP_2X: i32 = -766;
+    const SUBSAMP_GRAY: i32 = -765;
+
+    // the same as TigerVNC.
+    const QUALITY_MAP: [(u8, Subsampling); 10] = [
+        (15, Subsampling::S420),
+        (29, Subsampling::S420),
+        (41, Subsampling::S420),
+        (42, Subsampling::S422),
+        (62, Subsampling::S422),
+        (77, Subsampling::S422),
+        (79, Subsampling::S444),
+        (86, Subsampling::S444),
+        (92, Subsampling::S444),
+        (100, Subsampling::S444),
+    ];
+
+    /// The first one of each kind in the list wins.  The fine quality and the subsampling take precedence over
+    /// QualityLevel.
+    pub fn from_encodings(encodings: &[i32]) -> Self {
+        let mut compress_level = None;
+        let mut quality_level = None;
+        let mut fine_quality = None;
+        let mut subsampling = None;
+        for &e in encodings.iter() {
+            match e {
+                _ if Self::COMPRESS_LEVEL_0 <= e && e <= Self::COMPRESS_LEVEL_0 + 9 => {
+                    compress_level = compress_level.or(Some((e - Self::COMPRESS_LEVEL_0) as u8));
+                }
+                _ if Self::QUALITY_LEVEL_0 <= e && e <= Self::QUALITY_LEVEL_0 + 9 => {
+                    quality_level = quality_level.or(Some((e - Self::QUALITY_LEVEL_0) as usize));
+                }
+                _ if Self::FINE_QUALITY_LEVEL_0 < e && e <= Self::FINE_QUALITY_LEVEL_0 + 100 => {
+                    fine_quality = fine_quality.or(Some((e - Self::FINE_QUALITY_LEVEL_0) as u8));
+                }
+                Self::SUBSAMP_1X => subsampling = subsampling.or(Some(Subsampling::S444)),
+                Self::SUBSAMP_2X => subsampling = subsampling.or(Some(Subsampling::S422)),
+                Self::SUBSAMP_4X => subsampling = subsampling.or(Some(Subsampling::S420)),
+                Self::SUBSAMP_GRAY => subsampling = subsampling.or(Some(Subsampling::Gray)),
+                _ => (),
+            }
+        }
+
+        let mapped = quality_level.map(|i| Self::QUALITY_MAP[i]);
+        Options {
+            compress_level: compress_level.unwrap_or(Self::default().compress_level),
+            quality: fine_quality.or(mapped.map(|m| m.0)),
+            subsampling: subsampling.or(mapped.map(|m| m.1)),
+            ..Self::default()
+        }
+    }
+}
+
+/// The pixel format of the client.  The pixels of the screen, 0x00RRGGBB, are written in it by `pixel()` and
+/// `cpixel()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PixelFormat {
+    pub bits_per_pixel: u8,
+    pub depth: u8,
+    pub big_endian: bool,
+    pub true_colour: bool,
+    pub red_max: u16,
+    pub green_max: u16,
+    pub blue_max: u16,
+    pub red_shift: u8,
+    pub green_shift: u8,
+    pub blue_shift: u8,
+}
+
+impl PixelFormat {
+    /// The format of ServerInit: 32 bits in little endian, R, G and B from the lowest byte.
+    pub const SERVER: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_colour: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 0,
+        green_shift: 8,
+        blue_shift: 16,
+    };
+
+    /// Parses PIXEL_FORMAT of SetPixelFormat.
+    pub fn from_bytes(buf: &[u8; 16]) -> Self {
+        PixelFormat {
+            bits_per_pixel: buf[0],
+            depth: buf[1],
+            big_endian: buf[2] != 0,
+            true_colour: buf[3] != 0,
+            red_max: u16::from_be_bytes([buf[4], buf[5]]),
+            green_max: u16::from_be_bytes([buf[6], buf[7]]),
+            blue_max: u16::from_be_bytes([buf[8], buf[9]]),
+            red_shift: buf[10],
+            green_shift: buf[11],
+            blue_shift: buf[12],
+        }
+    }
+
+    /// Whether `pixel()` can write the format: true colour of 8, 16 or 32 bits.  Colour maps are not supported.
+    pub fn is_true_colour(&self) -> bool {
+        let fits = |max: u16, shift: u8| {
+            (max as u64)
+                .checked_shl(shift as u32)
+                .map_or(false, |v| v < 1 << self.bits_per_pixel)
+        };
+        self.true_colour
+            && (self.bits_per_pixel == 8 || self.bits_per_pixel == 16 || self.bits_per_pixel == 32)
+            && fits(self.red_max, self.red_shift)
+            && fits(self.green_max, self.green_shift)
+            && fits(self.blue_max, self.blue_shift)
+    }
+
+    /// Whether TPIXEL of Tight is R, G and B, i.e. 32 bit true colour of depth 24 with 8 bits per colour.  Tight
+    /// encoders support only these formats.
+    pub fn is_rgb888(&self) -> bool {
+        self.true_colour
+            && self.bits_per_pixel == 32
+            && self.depth == 24
+            && (self.red_max, self.green_max, self.blue_max) == (255, 255, 255)
+    }
+
+    /// The pixel value of the colour, scaled to the maxima.
+    pub fn pack(&self, c: u32) -> u32 {
+        let scale = |v: u32, max: u16, shift: u8| {
+            let v = if max == 255 { v } else { (v * max as u32 + 127) / 255 };
+            v.checked_shl(shift as u32).unwrap_or(0)
+        };
+        scale((c >> 16) & 0xff, self.red_max, self.red_shift)
+            | scale((c >> 8) & 0xff, self.green_max, self.green_shift)
+            | scale(c & 0xff, self.blue_max, self.blue_shift)
+    }
+
+    /// Appends PIXEL.
+    pub fn pixel(&self, out: &mut Vec<u8>, c: u32) {
+        let n = self.bits_per_pixel as usize / 8;
+        let v = self.pack(c);
+        if self.big_endian {
+            out.extend_from_slice(&v.to_be_bytes()[4 - n..]);
+        } else {
+            out.extend_from_slice(&v.to_le_bytes()[..n]);
+        }
+    }
+
+    /// # of bytes of CPIXEL of ZRLE and TRLE.
+    pub fn cpixel_len(&self) -> usize {
+        match self.cpixel_shift() {
+            Some(_) => 3,
+            None => self.bits_per_pixel as usize / 8,
+        }
+    }
+
+    /// Appends CPIXEL.
+    pub fn cpixel(&self, out: &mut Vec<u8>, c: u32) {
+        match self.cpixel_shift() {
+            Some(shift) if self.big_endian => out.extend_from_slice(&(self.pack(c) >> shift).to_be_bytes()[1..]),
+            Some(shift) => out.extend_from_slice(&(self.pack(c) >> shift).to_le_bytes()[..3]),
+            None => self.pixel(out, c),
+        }
+    }
+
+    // CPIXEL is the lower or the upper 3 bytes of a 32 bit pixel if the colours fit in them.  Returns its shift.
+    fn cpixel_shift(&self) -> Option<u32> {
+        if !self.true_colour || self.bits_per_pixel != 32 || self.depth > 24 {
+            return None;
+        }
+        let mask = self.pack(0x00ffffff);
+        if mask >> 24 == 0 {
+            Some(0)
+        } else if mask & 0xff == 0 {
+            Some(8)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for PixelFormat {
+    fn default() -> Self {
+        Self::SERVER
+    }
+}
+
+/// The largest rectangle which an encoder accepts.  Larger damage is tiled by `split()` before encoding.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Limits {
+    pub max_width: usize,
+    pub max_height: usize,
+    /// The budget of the encoded data of a rectangle, and its worst case per pixel.
+    pub max_bytes: usize,
+    pub max_bytes_per_pixel: usize,
+}
+
+impl Limits {
+    /// 16-bit sizes of the protocol.
+    pub const PROTOCOL: Limits = Limits {
+        max_width: 65535,
+        max_height: 65535,
+        max_bytes: usize::MAX,
+        max_bytes_per_pixel: 1,
+    };
+
+    /// Tight rectangles are up to 2048 pixels wide, and 3 bytes per pixel before compression.
+    pub const TIGHT: Limits = Limits {
+        max_width: 2048,
+        max_height: 65535,
+        // the compact length is below 1 << 22, with room for the overhead of zlib.
+        max_bytes: (1 << 22) - (1 << 16),
+        max_bytes_per_pixel: 3,
+    };
+
+    pub fn max_pixels(&self) -> usize {
+        self.max_bytes / self.max_bytes_per_pixel
+    }
+
+    /// Tiles the rectangle (x0, y0)-(x1, y1) into columns of at most `max_width`, and each column into bands of
+    /// at most `max_height` rows and `max_pixels()`.
+    pub fn split<F: FnMut(usize, usize, usize, usize)>(
+        &self,
+        x0: usize,
+        y0: usize,
+        x1: usize,
+        y1: usize,
+        callback: &mut F,
+    ) {
+        for bx in (x0..x1).step_by(self.max_width) {
+            let bx1 = cmp::min(bx + self.max_width, x1);
+            let bh = cmp::max(1, cmp::min(self.max_height, self.max_pixels() / (bx1 - bx)));
+            for by in (y0..y1).step_by(bh) {
+                callback(bx, by, bx1, cmp::min(by + bh, y1));
+            }
+        }
+    }
+}
+
 pub trait Encoder {
     fn new() -> Self;
     fn encode(&mut self, _: &mut Vec<u8>, _: &[u32], _: usize, _: usize, _: usize);
+
+    fn limits(&self) -> Limits {
+        Limits::PROTOCOL
+    }
+
+    /// Whether the encoder can write the pixels in `format`, which the client has set by SetPixelFormat.  The
+    /// server closes the connection otherwise.
+    fn supports_format(&self, format: &PixelFormat) -> bool {
+        format.is_rgb888()
+    }
+
+    /// Called when the client has changed its encodings or its pixel format.
+    fn set_options(&mut self, _: &Options) {}
+
+    /// Returns whether the rectangles written since the last call may be lossy.
+    fn take_lossy(&mut self) -> bool {
+        false
+    }
+
+    /// Encodes a rectangle losslessly, in place of the one sent lossy before.  Returns # of the rectangles written,
+    /// or 0 if the encoder has no lossless mode, which leaves the area queued.
+    fn refine_rect(&mut self, _: &mut Vec<u8>, _: &[u32], _: usize, _: usize, _: usize, _: usize, _: usize) -> usize {
+        0
+    }
+
+    /// Called after all the damage of a frame of size w x h has been passed to `encode_rect()` and
+    /// `encode_video_rect()`, for encoders which encode the frame as a whole.  Returns # of the rectangles written.
+    fn end_frame(&mut self, _: &mut Vec<u8>, _: &[u32], _: usize, _: usize, _: usize) -> usize {
+        0
+    }
+
+    /// Returns the rectangle (x0, y0, x1, y1) which `end_frame()` has written lossy since the last call, if any.
+    fn take_lossy_rect(&mut self) -> Option<(usize, usize, usize, usize)> {
+        None
+    }
+
+    /// The throughput of the connection estimated by the server, for encoders with rate control.
+    fn set_bitrate(&mut self, _: u64) {}
+
+    /// # of the instances which can encode the rectangles of a frame in parallel, e.g. # of the zlib streams of the
+    /// encoding.  1 if the rectangles must be encoded in order by a single instance.
+    fn max_workers(&self) -> usize {
+        1
+    }
+
+    /// Called on a new instance which is the `i`-th one encoding in parallel, before any other call.  It must use
+    /// only its share of the state of the connection, e.g. zlib stream `i`.
+    fn pin(&mut self, _: usize) {}
+
+    /// Encodes a rectangle which changes on most frames, e.g. a video player, in the same way as `encode_rect()`.
+    fn encode_video_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        self.encode_rect(out, screen, stride, x, y, w, h)
+    }
+
+    /// Encodes a rectangle at (x, y) including its header.  An encoder may split it into several rectangles.
+    /// Returns # of the rectangles written.
+    fn encode_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        write_rect_header(out, x, y, w, h);
+        self.encode(out, screen, stride, w, h);
+        1
+    }
+}
+
+pub fn write_rect_header(out: &mut Vec<u8>, x: usize, y: usize, w: usize, h: usize) {
+    out.extend(&[
+        (x >> 8) as u8,
+        x as u8,
+        (y >> 8) as u8,
+        y as u8,
+        (w >> 8) as u8,
+        w as u8,
+        (h >> 8) as u8,
+        h as u8,
+    ]);
 }
 
 pub struct RandomColorEncoder;
@@ -15,6 +384,10 @@ impl Encoder for RandomColorEncoder {
         RandomColorEncoder
     }
 
+    fn max_workers(&self) -> usize {
+        usize::MAX
+    }
+
     fn encode(&mut self, out: &mut Vec<u8>, _: &[u32], _: usize, _: usize, _: usize) {
         out.extend(&[
             0,
@@ -29,39 +402,56 @@ impl Encoder for RandomColorEncoder {
     }
 }
 
-pub struct RawEncoder;
+pub struct RawEncoder {
+    format: PixelFormat,
+}
+
+impl RawEncoder {
+    /// Appends the pixels of the rectangle in `format`.
+    pub fn pixels(out: &mut Vec<u8>, format: &PixelFormat, screen: &[u32], stride: usize, w: usize, h: usize) {
+        out.reserve(format.bits_per_pixel as usize / 8 * w * h);
+        for sy in (0..stride * h).step_by(stride) {
+            for &c in screen[sy..sy + w].iter() {
+                format.pixel(out, c);
+            }
+        }
+    }
+}
 
 impl Encoder for RawEncoder {
     fn new() -> Self {
-        RawEncoder
+        RawEncoder {
+            format: PixelFormat::SERVER,
+        }
+    }
+
+    fn supports_format(&self, format: &PixelFormat) -> bool {
+        format.is_true_colour()
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.format = options.format;
+    }
+
+    fn max_workers(&self) -> usize {
+        usize::MAX
     }
 
     fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
         out.extend(&[0, 0, 0, 0]); // encoding type: RAW.
-        let size = 4 * w * h;
-        out.reserve(size);
-        unsafe {
-            let mut src_u32 = screen.as_ptr();
-            let mut dst_u32 = out.as_mut_ptr().add(out.len()) as *mut u32;
-            for _ in 0..h {
-                ptr::copy_nonoverlapping(src_u32, dst_u32, w);
-                src_u32 = src_u32.add(stride);
-                dst_u32 = dst_u32.add(w);
-            }
-            let out_len = out.len();
-            out.set_len(out_len + size);
-        }
+        Self::pixels(out, &self.format, screen, stride, w, h);
     }
 }
 
-pub struct TightCompressor {
+/// A persistent zlib stream, flushed at the end of each rectangle so that the client can inflate it at once.
+pub struct ZlibStream {
     compressor: deflate::core::CompressorOxide,
     first: bool,
 }
 
-impl TightCompressor {
+impl ZlibStream {
     pub fn new() -> Self {
-        TightCompressor {
+        ZlibStream {
             compressor: deflate::core::CompressorOxide::new(
                 1 | deflate::core::deflate_flags::TDEFL_GREEDY_PARSING_FLAG,
             ),
@@ -69,15 +459,142 @@ impl TightCompressor {
         }
     }
 
-    pub fn compress(&mut self, src: &[u8], out: &mut Vec<u8>, stream: u8, filter: u8) {
+    /// zlib level, 0-9.
+    pub fn set_level(&mut self, level: u8) {
+        self.compressor.set_compression_level_raw(cmp::min(level, 9));
+    }
+
+    /// Discards the dictionary; the next output starts a new zlib stream.
+    pub fn reset(&mut self) {
+        self.compressor.reset();
+        self.first = true;
+    }
+
+    /// Appends the length in u32 and `src` compressed, as ZRLE and Zlib encodings.
+    pub fn write(&mut self, src: &[u8], out: &mut Vec<u8>) {
+        let len_index = out.len();
+        out.extend(&[0, 0, 0, 0]);
+        let ok = self.compress(src, out);
+        // there is no way to tell the client to reset, but the output grows as needed.
+        assert!(ok);
+        let zlib_len = (out.len() - len_index - 4) as u32;
+        out[len_index..len_index + 4].copy_from_slice(&zlib_len.to_be_bytes());
+    }
+
+    /// Appends `src` compressed to `out`, growing it as needed.  Returns false if the stream is broken and must be
+    /// reset.
+    pub fn compress(&mut self, src: &[u8], out: &mut Vec<u8>) -> bool {
+        let start = out.len();
+        if self.first {
+            out.extend(&[0x78, 0x01]);
+        }
+
+        let mut src = src;
+        let mut size = src.len() / 2 + 64;
+        let mut full = false;
+        loop {
+            let defl_index = out.len();
+            out.resize(defl_index + size, 0);
+            let (status, src_len, defl_len) = deflate::core::compress(
+                &mut self.compressor,
+                src,
+                &mut out[defl_index..],
+                deflate::core::TDEFLFlush::Sync,
+            );
+            out.truncate(defl_index + defl_len);
+            if status != deflate::core::TDEFLStatus::Okay {
+                out.truncate(start);
+                return false;
+            }
+            src = &src[src_len..];
+            // a full buffer leaves the rest pending in the compressor, and the next call only drains it without the
+            // flush.
+            if src.is_empty() && defl_len < size && !full {
+                break;
+            }
+            full = defl_len == size;
+            if full {
+                size *= 2;
+            }
+        }
+        self.first = false;
+        true
+    }
+
+    /// Appends `src` as the stored blocks of a new zlib stream, which the compressor does not continue.
+    pub fn store(src: &[u8], out: &mut Vec<u8>) {
+        out.extend(&[0x78, 0x01]);
+        for block in src.chunks(u16::MAX as usize) {
+            out.push(0); // not final, stored.
+            out.extend(&(block.len() as u16).to_le_bytes());
+            out.extend(&(!(block.len() as u16)).to_le_bytes());
+            out.extend_from_slice(block);
+        }
+    }
+}
+
+/// A zlib stream of Tight.  Each content class has its own stream so that its dictionary stays specialised.
+pub struct TightCompressor {
+    zlib: ZlibStream,
+    stream: u8,
+    reset: bool,
+}
+
+impl TightCompressor {
+    pub const STREAM_RAW: u8 = 0;
+    pub const STREAM_GRADIENT: u8 = 1;
+    pub const STREAM_PALETTE: u8 = 2;
+    pub const STREAM_MONO: u8 = 3;
+    pub const N_STREAMS: usize = 4;
+
+    pub fn new(stream: u8) -> Self {
+        assert!((stream as usize) < Self::N_STREAMS);
+        TightCompressor {
+            zlib: ZlibStream::new(),
+            stream,
+            reset: false,
+        }
+    }
+
+    pub fn set_level(&mut self, level: u8) {
+        self.zlib.set_level(level);
+    }
+
+    /// Discards the dictionary.  The client is told to reset its stream by the next rectangle.
+    pub fn reset(&mut self) {
+        self.zlib.reset();
+        self.reset = true;
+    }
+
+    /// `filter` is the filter type followed by its parameters, e.g. the palette.
+    pub fn compress(&mut self, src: &[u8], out: &mut Vec<u8>, filter: &[u8]) {
+        let start = out.len();
+        if self.try_compress(src, out, filter, false) {
+            return;
+        }
+        // the client would inflate a broken block; start over with a fresh stream.
+        out.truncate(start);
+        self.reset();
+        if self.try_compress(src, out, filter, false) {
+            return;
+        }
+        // give up compressing, and reset the client again before the next block of the compressor.
+        out.truncate(start);
+        self.reset();
+        self.try_compress(src, out, filter, true);
+        self.reset = true;
+    }
+
+    fn try_compress(&mut self, src: &[u8], out: &mut Vec<u8>, filter: &[u8], stored: bool) -> bool {
+        let reset_bits = if self.reset { 1 << self.stream } else { 0 };
         out.extend(&[
             0,
             0,
             0,
-            7,                           // encoding type: Tight.
-            0b0100_0000 | (stream << 4), // compression control.
-            filter,                      // filter type: gradient.
+            7,                                             // encoding type: Tight.
+            0b0100_0000 | (self.stream << 4) | reset_bits, // compression control.
         ]);
+        out.extend_from_slice(filter);
 
         if src.len() < 12 {
             out.extend_from_slice(src);
@@ -86,100 +603,420 @@ impl TightCompressor {
             out.extend(&[0, 0, 0]);
 
             let zlib_index = out.len();
-            if self.first {
-                out.extend(&[0x78, 0x01]);
-                self.first = false;
+            if stored {
+                ZlibStream::store(src, out);
+            } else if !self.zlib.compress(src, out) {
+                return false;
             }
 
-            let defl_index = out.len();
-            let capacity = out.capacity();
-            unsafe { out.set_len(capacity) };
-            let (_, _, defl_len) = deflate::core::compress(
-                &mut self.compressor,
-                src,
-                &mut out[defl_index..],
-                deflate::core::TDEFLFlush::Sync,
-            );
-            unsafe { out.set_len(defl_index + defl_len) };
-
             let zlib_len = out.len() - zlib_index;
             assert!(zlib_len < 1 << 22);
             out[len_index + 0] = 0x80 | (zlib_len & 0x7f) as u8;
             out[len_index + 1] = 0x80 | ((zlib_len >> 7) & 0x7f) as u8;
             out[len_index + 2] = (zlib_len >> 14) as u8;
         }
+        self.reset = false;
+        true
     }
 }
 
-pub struct TightRawEncoder {
-    buffer: Vec<u8>,
-    compressor: TightCompressor,
-}
+/// Solid colour detection and the extraction of large solid areas, in the same way as TightVNC.
+pub struct TightSolid;
 
-impl Encoder for TightRawEncoder {
-    fn new() -> Self {
-        TightRawEncoder {
-            buffer: Vec::new(),
-            compressor: TightCompressor::new(),
+impl TightSolid {
+    const MIN_SPLIT_RECT_SIZE: usize = 4096;
+    const MIN_SOLID_SUBRECT_SIZE: usize = 2048;
+    const MAX_SPLIT_TILE_SIZE: usize = 16;
+    // the same as TightVNC.  The worst case of any compression of this many pixels fits in the compact length.
+    const MAX_RECT_PIXELS: usize = 65536;
+
+    /// Returns the colour if the rectangle is filled with a single colour.
+    pub fn color(screen: &[u32], stride: usize, w: usize, h: usize) -> Option<u32> {
+        let c = screen[0] & 0x00ffffff;
+        if Self::check(screen, stride, 0, 0, w, h, c) {
+            Some(c)
+        } else {
+            None
         }
     }
 
-    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
-        let len = 3 * w * h;
-        if self.buffer.capacity() < len + 1 {
-            self.buffer = Vec::with_capacity(len + 1);
+    /// `encoding` is `TIGHT` or `TIGHT_PNG`.
+    pub fn fill(out: &mut Vec<u8>, encoding: i32, c: u32) {
+        out.extend(&encoding.to_be_bytes());
+        out.extend(&[
+            0b1000_0000, // compression control: fill.
+            (c >> 16) as u8,
+            (c >> 8) as u8,
+            c as u8,
+        ]);
+    }
+
+    /// Cuts large solid areas out of the rectangle as fill rectangles, and encodes the rest by `encoder`.
+    pub fn encode_rect<E: Encoder>(
+        encoder: &mut E,
+        out: &mut Vec<u8>,
+        encoding: i32,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        let (sx0, sy0, sx1, sy1, c) = match Self::find(screen, stride, w, h) {
+            Some(area) => area,
+            None => return Self::encode_bands(encoder, out, screen, stride, x, y, w, h),
+        };
+
+        let mut n = 0;
+        let mut rest = |x0: usize, y0: usize, x1: usize, y1: usize| {
+            if x0 < x1 && y0 < y1 {
+                let screen = &screen[stride * y0 + x0..];
+                n += Self::encode_rect(encoder, out, encoding, screen, stride, x + x0, y + y0, x1 - x0, y1 - y0);
+            }
+        };
+        rest(0, 0, w, sy0);
+        rest(0, sy0, sx0, sy1);
+        rest(sx1, sy0, w, sy1);
+        rest(0, sy1, w, h);
+        write_rect_header(out, x + sx0, y + sy0, sx1 - sx0, sy1 - sy0);
+        Self::fill(out, encoding, c);
+        n + 1
+    }
+
+    /// Encodes the rectangle by `encoder` in bands of up to `MAX_RECT_PIXELS`.
+    pub fn encode_bands<E: Encoder>(
+        encoder: &mut E,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        let bh = cmp::max(1, Self::MAX_RECT_PIXELS / w);
+        let mut n = 0;
+        for by in (0..h).step_by(bh) {
+            let bh = cmp::min(bh, h - by);
+            write_rect_header(out, x, y + by, w, bh);
+            encoder.encode(out, &screen[stride * by..], stride, w, bh);
+            n += 1;
         }
+        n
+    }
 
-        let screen = screen.as_ptr() as *const u8x4;
-        unsafe {
-            self.buffer.set_len(len);
-            let mut buffer = self.buffer.as_mut_ptr();
-            for sy in (0..stride * h).step_by(stride) {
-                let s00 = screen.add(sy);
-                for x in 0..w {
-                    let dst = *s00.add(x);
-                    ptr::write_unaligned(buffer as *mut u8x4, shuffle!(dst, [2, 1, 0, 3]));
-                    buffer = buffer.add(3);
+    fn check(screen: &[u32], stride: usize, x0: usize, y0: usize, x1: usize, y1: usize, c: u32) -> bool {
+        (y0..y1).all(|y| {
+            screen[stride * y + x0..stride * y + x1]
+                .iter()
+                .all(|&p| p & 0x00ffffff == c)
+        })
+    }
+
+    // returns the solid area as (x0, y0, x1, y1, colour).
+    fn find(screen: &[u32], stride: usize, w: usize, h: usize) -> Option<(usize, usize, usize, usize, u32)> {
+        if w * h < Self::MIN_SPLIT_RECT_SIZE {
+            return None;
+        }
+        let tile = Self::MAX_SPLIT_TILE_SIZE;
+        for dy in (0..h).step_by(tile) {
+            let dh = cmp::min(tile, h - dy);
+            for dx in (0..w).step_by(tile) {
+                let dw = cmp::min(tile, w - dx);
+                let c = screen[stride * dy + dx] & 0x00ffffff;
+                if !Self::check(screen, stride, dx, dy, dx + dw, dy + dh, c) {
+                    continue;
+                }
+                let (bw, bh) = Self::find_best(screen, stride, dx, dy, w - dx, h - dy, c);
+                if bw * bh == w * h {
+                    // the whole rectangle is solid; a single fill rectangle is enough.
+                    return None;
+                }
+                if bw * bh < Self::MIN_SOLID_SUBRECT_SIZE {
+                    continue;
+                }
+                let (x0, y0, x1, y1) = Self::extend(screen, stride, w, h, (dx, dy, dx + bw, dy + bh), c);
+                return Some((x0, y0, x1, y1, c));
+            }
+        }
+        None
+    }
+
+    // grows the area from the tile at (x, y) tile by tile, narrowing it row by row, and keeps the largest one.
+    fn find_best(screen: &[u32], stride: usize, x: usize, y: usize, w: usize, h: usize, c: u32) -> (usize, usize) {
+        let tile = Self::MAX_SPLIT_TILE_SIZE;
+        let mut w_prev = w;
+        let mut best = (0, 0);
+        for dy in (y..y + h).step_by(tile) {
+            let dh = cmp::min(tile, y + h - dy);
+            let dw = cmp::min(tile, w_prev);
+            if !Self::check(screen, stride, x, dy, x + dw, dy + dh, c) {
+                break;
+            }
+            let mut dx = x + dw;
+            while dx < x + w_prev {
+                let dw = cmp::min(tile, x + w_prev - dx);
+                if !Self::check(screen, stride, dx, dy, dx + dw, dy + dh, c) {
+                    break;
                 }
+                dx += dw;
+            }
+            w_prev = dx - x;
+            if w_prev * (dy + dh - y) > best.0 * best.1 {
+                best = (w_prev, dy + dh - y);
             }
         }
+        best
+    }
 
-        self.compressor.compress(&self.buffer, out, 0, 0);
+    // extends the area pixel by pixel in each direction.
+    fn extend(
+        screen: &[u32],
+        stride: usize,
+        w: usize,
+        h: usize,
+        (mut x0, mut y0, mut x1, mut y1): (usize, usize, usize, usize),
+        c: u32,
+    ) -> (usize, usize, usize, usize) {
+        while y0 > 0 && Self::check(screen, stride, x0, y0 - 1, x1, y0, c) {
+            y0 -= 1;
+        }
+        while y1 < h && Self::check(screen, stride, x0, y1, x1, y1 + 1, c) {
+            y1 += 1;
+        }
+        while x0 > 0 && Self::check(screen, stride, x0 - 1, y0, x0, y1, c) {
+            x0 -= 1;
+        }
+        while x1 < w && Self::check(screen, stride, x1, y0, x1 + 1, y1, c) {
+            x1 += 1;
+        }
+        (x0, y0, x1, y1)
     }
 }
 
-pub struct TightGradientEncoder {
-    buffer: Vec<u8>,
-    compressor: TightCompressor,
+/// Colour table of a rectangle for the Tight palette filter.
+pub struct TightPalette {
+    colors: Vec<u32>,
+    keys: Vec<u32>,
+    indices: Vec<u8>,
 }
 
-impl Encoder for TightGradientEncoder {
-    fn new() -> Self {
-        TightGradientEncoder {
-            buffer: Vec::new(),
-            compressor: TightCompressor::new(),
+impl TightPalette {
+    const HASH_BITS: usize = 10;
+
+    pub fn new() -> Self {
+        TightPalette {
+            colors: Vec::with_capacity(256),
+            keys: vec![!0; 1 << Self::HASH_BITS],
+            indices: vec![0; 1 << Self::HASH_BITS],
         }
     }
 
-    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
-        let len = 3 * w * h;
-        if self.buffer.capacity() < len + 1 {
-            self.buffer = Vec::with_capacity(len + 1);
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn colors(&self) -> &[u32] {
+        &self.colors
+    }
+
+    /// Collects the colours of the rectangle.  Returns false if there are more than `max` colours.
+    pub fn build(&mut self, screen: &[u32], stride: usize, w: usize, h: usize, max: usize) -> bool {
+        // in the reverse order of insertion, otherwise a hole breaks the probe chains of the later colours.
+        for &c in self.colors.iter().rev() {
+            let i = self.find(c);
+            self.keys[i] = !0;
         }
+        self.colors.clear();
 
-        let screen = screen.as_ptr() as *const u8x4;
-        unsafe {
-            self.buffer.set_len(len);
-            let mut buffer = self.buffer.as_mut_ptr();
-            let s00 = screen;
-            let s01 = screen.sub(stride);
-            /* y == y0 */
-            {
-                let mut v10 = u8x4::splat(0);
-                for x in 0..w {
-                    let v00 = *s00.add(x);
-                    let dst = v00 - v10;
-                    ptr::write_unaligned(buffer as *mut u8x4, shuffle!(dst, [2, 1, 0, 3]));
+        let mut prev = !0;
+        for sy in (0..stride * h).step_by(stride) {
+            for &c in screen[sy..sy + w].iter() {
+                let c = c & 0x00ffffff;
+                if c == prev {
+                    continue;
+                }
+                prev = c;
+                let i = self.find(c);
+                if self.keys[i] == !0 {
+                    if self.colors.len() >= max {
+                        return false;
+                    }
+                    self.keys[i] = c;
+                    self.indices[i] = self.colors.len() as u8;
+                    self.colors.push(c);
+                }
+            }
+        }
+        true
+    }
+
+    fn find(&self, c: u32) -> usize {
+        let mask = (1 << Self::HASH_BITS) - 1;
+        let mut i = (c.wrapping_mul(0x9e37_79b1) >> (32 - Self::HASH_BITS)) as usize;
+        while self.keys[i] != c && self.keys[i] != !0 {
+            i = (i + 1) & mask;
+        }
+        i
+    }
+
+    fn index(&self, c: u32) -> u8 {
+        self.indices[self.find(c & 0x00ffffff)]
+    }
+
+    /// The filter type and its parameters: the palette filter, # of colours - 1 and the colours in TPIXEL.
+    pub fn filter(&self, filter: &mut Vec<u8>) {
+        filter.clear();
+        filter.push(1);
+        filter.push((self.colors.len() - 1) as u8);
+        for &c in self.colors.iter() {
+            filter.extend(&[(c >> 16) as u8, (c >> 8) as u8, c as u8]);
+        }
+    }
+
+    /// Writes the indices; 1 bit per pixel (MSB first, each row padded to a byte) for 2 colours, 8 bits otherwise.
+    pub fn indices(&self, buffer: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        buffer.clear();
+        let mut prev = (!0, 0);
+        let mut index = |c: u32| {
+            if c != prev.0 {
+                prev = (c, self.index(c));
+            }
+            prev.1
+        };
+        if self.colors.len() <= 2 {
+            for sy in (0..stride * h).step_by(stride) {
+                for chunk in screen[sy..sy + w].chunks(8) {
+                    let mut bits = 0;
+                    for (i, &c) in chunk.iter().enumerate() {
+                        bits |= index(c) << (7 - i);
+                    }
+                    buffer.push(bits);
+                }
+            }
+        } else {
+            for sy in (0..stride * h).step_by(stride) {
+                buffer.extend(screen[sy..sy + w].iter().map(|&c| index(c)));
+            }
+        }
+    }
+}
+
+pub struct TightRawEncoder {
+    buffer: Vec<u8>,
+    compressor: TightCompressor,
+}
+
+impl Encoder for TightRawEncoder {
+    fn new() -> Self {
+        TightRawEncoder {
+            buffer: Vec::new(),
+            compressor: TightCompressor::new(TightCompressor::STREAM_RAW),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits::TIGHT
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.compressor.set_level(options.compress_level);
+    }
+
+    fn max_workers(&self) -> usize {
+        TightCompressor::N_STREAMS
+    }
+
+    fn pin(&mut self, i: usize) {
+        self.compressor = TightCompressor::new(i as u8);
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        let len = 3 * w * h;
+        if self.buffer.capacity() < len + 1 {
+            self.buffer = Vec::with_capacity(len + 1);
+        }
+
+        let screen = screen.as_ptr() as *const u8x4;
+        unsafe {
+            self.buffer.set_len(len);
+            let mut buffer = self.buffer.as_mut_ptr();
+            for sy in (0..stride * h).step_by(stride) {
+                let s00 = screen.add(sy);
+                for x in 0..w {
+                    let dst = *s00.add(x);
+                    ptr::write_unaligned(buffer as *mut u8x4, shuffle!(dst, [2, 1, 0, 3]));
+                    buffer = buffer.add(3);
+                }
+            }
+        }
+
+        self.compressor.compress(&self.buffer, out, &[0]);
+    }
+
+    fn encode_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        TightSolid::encode_bands(self, out, screen, stride, x, y, w, h)
+    }
+}
+
+pub struct TightGradientEncoder {
+    buffer: Vec<u8>,
+    compressor: TightCompressor,
+}
+
+impl Encoder for TightGradientEncoder {
+    fn new() -> Self {
+        TightGradientEncoder {
+            buffer: Vec::new(),
+            compressor: TightCompressor::new(TightCompressor::STREAM_GRADIENT),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits::TIGHT
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.compressor.set_level(options.compress_level);
+    }
+
+    fn max_workers(&self) -> usize {
+        TightCompressor::N_STREAMS
+    }
+
+    fn pin(&mut self, i: usize) {
+        self.compressor = TightCompressor::new(i as u8);
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        let len = 3 * w * h;
+        if self.buffer.capacity() < len + 1 {
+            self.buffer = Vec::with_capacity(len + 1);
+        }
+
+        let screen = screen.as_ptr() as *const u8x4;
+        unsafe {
+            self.buffer.set_len(len);
+            let mut buffer = self.buffer.as_mut_ptr();
+            let s00 = screen;
+            let s01 = screen.sub(stride);
+            /* y == y0 */
+            {
+                let mut v10 = u8x4::splat(0);
+                for x in 0..w {
+                    let v00 = *s00.add(x);
+                    let dst = v00 - v10;
+                    ptr::write_unaligned(buffer as *mut u8x4, shuffle!(dst, [2, 1, 0, 3]));
                     buffer = buffer.add(3);
                     v10 = v00;
                 }
@@ -202,28 +1039,42 @@ impl Encoder for TightGradientEncoder {
             }
         }
 
-        self.compressor.compress(&mut self.buffer, out, 0, 2);
+        self.compressor.compress(&self.buffer, out, &[2]);
+    }
+
+    fn encode_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        TightSolid::encode_bands(self, out, screen, stride, x, y, w, h)
     }
 }
 
 pub struct TightAdaptiveEncoder {
     buffer_raw: Vec<u8>,
     buffer_lin: Vec<u8>,
-    compressor_raw: TightCompressor,
-    compressor_lin: TightCompressor,
+    buffer_pal: Vec<u8>,
+    filter_pal: Vec<u8>,
+    palette: TightPalette,
+    // indexed by the stream ids.
+    compressors: [TightCompressor; TightCompressor::N_STREAMS],
+    pinned: Option<u8>,
 }
 
-impl Encoder for TightAdaptiveEncoder {
-    fn new() -> Self {
-        TightAdaptiveEncoder {
-            buffer_raw: Vec::new(),
-            buffer_lin: Vec::new(),
-            compressor_raw: TightCompressor::new(),
-            compressor_lin: TightCompressor::new(),
-        }
+impl TightAdaptiveEncoder {
+    // the stream for the content, or the only one of a pinned instance.
+    fn stream(&self, stream: u8) -> usize {
+        self.pinned.unwrap_or(stream) as usize
     }
 
-    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+    // fills the raw and the gradient filtered buffers, and returns the estimated ratios of their sizes before zlib.
+    fn filter(&mut self, screen_u32: &[u32], stride: usize, w: usize, h: usize) -> (f64, f64) {
         let len = 3 * w * h;
         if self.buffer_raw.capacity() < len + 1 {
             self.buffer_raw = Vec::with_capacity(len + 1);
@@ -232,7 +1083,7 @@ impl Encoder for TightAdaptiveEncoder {
             self.buffer_lin = Vec::with_capacity(len + 1);
         }
 
-        let screen = screen.as_ptr() as *const u8x4;
+        let screen = screen_u32.as_ptr() as *const u8x4;
         let mut sum_l1 = i32x4::splat(0);
         let mut n_matches = 0;
         unsafe {
@@ -303,58 +1154,191 @@ impl Encoder for TightAdaptiveEncoder {
         } else {
             (1.0 / f64::ln(2.0) + 1.0) / 8.0 + (1.0 / 8.0) * f64::log2(m / (3 * n_pixels) as f64)
         };
+        (raw_ratio, lin_ratio)
+    }
 
-        if raw_ratio < lin_ratio {
-            self.compressor_raw.compress(&self.buffer_raw, out, 0, 0);
+    // the estimated size of the palette filter before zlib, after `palette.build()`.
+    fn palette_size(&self, raw_ratio: f64, n_pixels: usize) -> f64 {
+        let n_colors = self.palette.len();
+        let bits = if n_colors <= 2 { 1.0 } else { 8.0 };
+        (3 * n_colors) as f64 + f64::max(raw_ratio, 1.0 / 64.0) * bits / 8.0 * n_pixels as f64
+    }
+
+    // compresses `buffer_pal` after `palette.indices()`.
+    fn compress_palette(&mut self, out: &mut Vec<u8>) {
+        self.palette.filter(&mut self.filter_pal);
+        let i = if self.palette.len() <= 2 {
+            self.stream(TightCompressor::STREAM_MONO)
         } else {
-            self.compressor_lin.compress(&self.buffer_lin, out, 1, 2);
-        }
+            self.stream(TightCompressor::STREAM_PALETTE)
+        };
+        self.compressors[i].compress(&self.buffer_pal, out, &self.filter_pal);
     }
-}
 
-pub struct TightJpegEncoder {
-    compressor: *mut ffi::c_void,
-}
+    fn compress_raw(&mut self, out: &mut Vec<u8>) {
+        let i = self.stream(TightCompressor::STREAM_RAW);
+        self.compressors[i].compress(&self.buffer_raw, out, &[0]);
+    }
 
-impl Drop for TightJpegEncoder {
-    fn drop(&mut self) {
-        unsafe { jpeg_compressor_destroy(self.compressor) };
+    fn compress_gradient(&mut self, out: &mut Vec<u8>) {
+        let i = self.stream(TightCompressor::STREAM_GRADIENT);
+        self.compressors[i].compress(&self.buffer_lin, out, &[2]);
     }
 }
 
-impl Encoder for TightJpegEncoder {
+impl Encoder for TightAdaptiveEncoder {
     fn new() -> Self {
-        let compressor = unsafe { jpeg_compressor_create() };
-        TightJpegEncoder { compressor: compressor }
+        TightAdaptiveEncoder {
+            buffer_raw: Vec::new(),
+            buffer_lin: Vec::new(),
+            buffer_pal: Vec::new(),
+            filter_pal: Vec::new(),
+            palette: TightPalette::new(),
+            compressors: [
+                TightCompressor::new(TightCompressor::STREAM_RAW),
+                TightCompressor::new(TightCompressor::STREAM_GRADIENT),
+                TightCompressor::new(TightCompressor::STREAM_PALETTE),
+                TightCompressor::new(TightCompressor::STREAM_MONO),
+            ],
+            pinned: None,
+        }
     }
 
-    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
-        out.extend(&[
-            0,
-            0,
-            0,
-            7,           // encoding type: Tight.
-            0b1001_0000, // compression control: JPEG.
-        ]);
+    fn limits(&self) -> Limits {
+        Limits::TIGHT
+    }
 
-        let len_index = out.len();
-        out.extend(&[0, 0, 0]);
+    fn set_options(&mut self, options: &Options) {
+        for compressor in self.compressors.iter_mut() {
+            compressor.set_level(options.compress_level);
+        }
+    }
 
-        let jpeg_index = out.len();
-        let jpeg_len;
+    fn max_workers(&self) -> usize {
+        TightCompressor::N_STREAMS
+    }
+
+    fn pin(&mut self, i: usize) {
+        self.pinned = Some(i as u8);
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen_u32: &[u32], stride: usize, w: usize, h: usize) {
+        if let Some(c) = TightSolid::color(screen_u32, stride, w, h) {
+            TightSolid::fill(out, TIGHT, c);
+            return;
+        }
+
+        let (raw_ratio, lin_ratio) = self.filter(screen_u32, stride, w, h);
+
+        // estimated sizes before zlib.
+        let len = 3 * w * h;
+        let raw_size = raw_ratio * len as f64;
+        let lin_size = lin_ratio * len as f64;
+        if self.palette.build(screen_u32, stride, w, h, 256) && self.palette.len() >= 2 {
+            let pal_size = self.palette_size(raw_ratio, w * h);
+            if pal_size < f64::min(raw_size, lin_size) {
+                self.palette.indices(&mut self.buffer_pal, screen_u32, stride, w, h);
+                self.compress_palette(out);
+                return;
+            }
+        }
+
+        if raw_ratio < lin_ratio {
+            self.compress_raw(out);
+        } else {
+            self.compress_gradient(out);
+        }
+    }
+
+    fn encode_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        TightSolid::encode_rect(self, out, TIGHT, screen, stride, x, y, w, h)
+    }
+}
+
+// libjpeg through src/jpeg_compressor.c.
+#[cfg(not(feature = "rust-jpeg"))]
+struct JpegCompressor(*mut ffi::c_void);
+
+#[cfg(not(feature = "rust-jpeg"))]
+impl JpegCompressor {
+    fn new() -> Self {
+        JpegCompressor(unsafe { jpeg_compressor_create() })
+    }
+
+    fn compress(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        w: usize,
+        h: usize,
+        quality: u8,
+        subsampling: Subsampling,
+    ) {
+        // the data stays in the buffer of the compressor until the next call.
+        let mut data = ptr::null();
         unsafe {
-            jpeg_len = jpeg_compressor_compress(
-                self.compressor,
-                out.as_mut_ptr().add(jpeg_index),
-                out.capacity() - jpeg_index,
+            let len = jpeg_compressor_compress(
+                self.0,
+                &mut data,
                 screen.as_ptr(),
                 stride,
                 w,
                 h,
+                quality as i32,
+                subsampling as i32,
             );
-            out.set_len(jpeg_index + jpeg_len);
+            out.extend_from_slice(slice::from_raw_parts(data, len));
+        }
+    }
+}
+
+#[cfg(not(feature = "rust-jpeg"))]
+impl Drop for JpegCompressor {
+    fn drop(&mut self) {
+        unsafe { jpeg_compressor_destroy(self.0) };
+    }
+}
+
+pub struct TightJpegEncoder {
+    compressor: JpegCompressor,
+    quality: u8,
+    subsampling: Subsampling,
+    lossy: bool,
+    // created by the first refinement.
+    lossless: Option<Box<TightAdaptiveEncoder>>,
+    options: Options,
+}
+
+impl TightJpegEncoder {
+    /// `encoding` is `TIGHT` or `TIGHT_PNG`.
+    pub fn encode_as(&mut self, out: &mut Vec<u8>, encoding: i32, screen: &[u32], stride: usize, w: usize, h: usize) {
+        if let Some(c) = TightSolid::color(screen, stride, w, h) {
+            TightSolid::fill(out, encoding, c);
+            return;
         }
 
+        self.lossy = true;
+        out.extend(&encoding.to_be_bytes());
+        out.push(0b1001_0000); // compression control: JPEG.
+
+        let len_index = out.len();
+        out.extend(&[0, 0, 0]);
+
+        let jpeg_index = out.len();
+        self.compressor
+            .compress(out, screen, stride, w, h, self.quality, self.subsampling);
+        let jpeg_len = out.len() - jpeg_index;
+
         assert!(jpeg_len < 1 << 22);
         out[len_index + 0] = 0x80 | (jpeg_len & 0x7f) as u8;
         out[len_index + 1] = 0x80 | ((jpeg_len >> 7) & 0x7f) as u8;
@@ -362,16 +1346,1584 @@ impl Encoder for TightJpegEncoder {
     }
 }
 
-extern {
-    fn jpeg_compressor_create() -> *mut ffi::c_void;
-    fn jpeg_compressor_destroy(this: *mut ffi::c_void);
-    fn jpeg_compressor_compress(
-        this: *mut ffi::c_void,
-        dst: *mut u8,
-        dst_size: usize,
-        src: *const u32,
+impl Encoder for TightJpegEncoder {
+    fn new() -> Self {
+        TightJpegEncoder {
+            compressor: JpegCompressor::new(),
+            quality: 93,
+            subsampling: Subsampling::S444,
+            lossy: false,
+            lossless: None,
+            options: Options::default(),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits::TIGHT
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.quality = options.quality.unwrap_or(93);
+        self.subsampling = options.subsampling.unwrap_or(Subsampling::S444);
+        self.options = *options;
+        if let Some(lossless) = self.lossless.as_mut() {
+            lossless.set_options(options);
+        }
+    }
+
+    fn max_workers(&self) -> usize {
+        usize::MAX
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        self.encode_as(out, TIGHT, screen, stride, w, h);
+    }
+
+    fn take_lossy(&mut self) -> bool {
+        mem::replace(&mut self.lossy, false)
+    }
+
+    fn refine_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
         stride: usize,
+        x: usize,
+        y: usize,
         w: usize,
         h: usize,
-    ) -> usize;
+    ) -> usize {
+        let options = self.options;
+        let lossless = self.lossless.get_or_insert_with(|| {
+            let mut lossless = Box::new(TightAdaptiveEncoder::new());
+            lossless.set_options(&options);
+            lossless
+        });
+        lossless.encode_rect(out, screen, stride, x, y, w, h)
+    }
+
+    fn encode_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        TightSolid::encode_rect(self, out, TIGHT, screen, stride, x, y, w, h)
+    }
+}
+
+/// Sends photographic or video rectangles as JPEG and the others, e.g. text and UI, losslessly.
+pub struct TightMixedEncoder {
+    lossless: TightAdaptiveEncoder,
+    jpeg: TightJpegEncoder,
+    palette: TightPalette,
+}
+
+impl TightMixedEncoder {
+    const MIN_JPEG_PIXELS: usize = 4096;
+    const MAX_UI_COLORS: usize = 24;
+    const EDGE_THRESHOLD: i32 = 48;
+
+    // UI has few colours, large flat areas and sharp edges; photos are rarely flat and change smoothly.
+    fn is_photographic(palette: &mut TightPalette, screen: &[u32], stride: usize, w: usize, h: usize) -> bool {
+        if w * h < Self::MIN_JPEG_PIXELS || w < 8 || h < 8 {
+            return false;
+        }
+        if palette.build(screen, stride, w, h, Self::MAX_UI_COLORS) {
+            return false;
+        }
+
+        let mut n_pixels = 0;
+        let mut n_flats = 0;
+        let mut n_edges = 0;
+        // every other row is enough.
+        for y in (1..h).step_by(2) {
+            let row = &screen[stride * y..];
+            let upper = &screen[stride * (y - 1)..];
+            for x in 1..w {
+                let v00 = row[x] & 0x00ffffff;
+                let v10 = row[x - 1] & 0x00ffffff;
+                n_pixels += 1;
+                if v00 == v10 {
+                    n_flats += 1;
+                    continue;
+                }
+                let v01 = upper[x];
+                let v11 = upper[x - 1];
+                let error = (0..3)
+                    .map(|i| {
+                        let c = |v: u32| ((v >> (8 * i)) & 0xff) as i32;
+                        let prd = cmp::max(0, cmp::min(255, c(v01) + c(v10) - c(v11)));
+                        (c(v00) - prd).abs()
+                    })
+                    .max()
+                    .unwrap();
+                if error > Self::EDGE_THRESHOLD {
+                    n_edges += 1;
+                }
+            }
+        }
+        2 * n_flats < n_pixels && 8 * n_edges < n_pixels
+    }
+}
+
+impl Encoder for TightMixedEncoder {
+    fn new() -> Self {
+        TightMixedEncoder {
+            lossless: TightAdaptiveEncoder::new(),
+            jpeg: TightJpegEncoder::new(),
+            palette: TightPalette::new(),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits::TIGHT
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.lossless.set_options(options);
+        self.jpeg.set_options(options);
+    }
+
+    fn max_workers(&self) -> usize {
+        self.lossless.max_workers()
+    }
+
+    fn pin(&mut self, i: usize) {
+        self.lossless.pin(i);
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        if Self::is_photographic(&mut self.palette, screen, stride, w, h) {
+            self.jpeg.encode(out, screen, stride, w, h);
+        } else {
+            self.lossless.encode(out, screen, stride, w, h);
+        }
+    }
+
+    fn take_lossy(&mut self) -> bool {
+        self.jpeg.take_lossy()
+    }
+
+    fn refine_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        self.lossless.encode_rect(out, screen, stride, x, y, w, h)
+    }
+
+    fn encode_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        TightSolid::encode_rect(self, out, TIGHT, screen, stride, x, y, w, h)
+    }
+}
+
+/// Sends the areas playing videos as JPEG and the rest of the screen losslessly.
+pub struct TightVideoRegionEncoder {
+    lossless: TightAdaptiveEncoder,
+    jpeg: TightJpegEncoder,
+}
+
+impl Encoder for TightVideoRegionEncoder {
+    fn new() -> Self {
+        TightVideoRegionEncoder {
+            lossless: TightAdaptiveEncoder::new(),
+            jpeg: TightJpegEncoder::new(),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits::TIGHT
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.lossless.set_options(options);
+        self.jpeg.set_options(options);
+    }
+
+    fn max_workers(&self) -> usize {
+        self.lossless.max_workers()
+    }
+
+    fn pin(&mut self, i: usize) {
+        self.lossless.pin(i);
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        self.lossless.encode(out, screen, stride, w, h);
+    }
+
+    fn take_lossy(&mut self) -> bool {
+        self.jpeg.take_lossy()
+    }
+
+    fn refine_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        self.lossless.encode_rect(out, screen, stride, x, y, w, h)
+    }
+
+    fn encode_video_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        self.jpeg.encode_rect(out, screen, stride, x, y, w, h)
+    }
+
+    fn encode_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        self.lossless.encode_rect(out, screen, stride, x, y, w, h)
+    }
+}
+
+// the ratio of the actual compressed size to an estimate, learnt online as a moving mean and variance of its log.
+struct CostModel {
+    mean: f64,
+    var: f64,
+    n: usize,
+}
+
+impl CostModel {
+    const ALPHA: f64 = 0.1;
+    const MIN_SAMPLES: usize = 8;
+
+    fn new() -> Self {
+        CostModel {
+            mean: 0.0,
+            var: 0.0,
+            n: 0,
+        }
+    }
+
+    fn ready(&self) -> bool {
+        self.n >= Self::MIN_SAMPLES
+    }
+
+    // the log of the predicted size.
+    fn predict(&self, estimate: f64) -> f64 {
+        estimate.ln() + self.mean
+    }
+
+    fn update(&mut self, estimate: f64, actual: f64) {
+        if actual <= 0.0 {
+            return;
+        }
+        self.n += 1;
+        // a plain mean of the first samples.
+        let alpha = f64::max(Self::ALPHA, 1.0 / self.n as f64);
+        let e = actual.ln() - self.predict(estimate);
+        self.mean += alpha * e;
+        self.var = (1.0 - alpha) * (self.var + alpha * e * e);
+    }
+}
+
+/// Chooses raw, gradient, palette or JPEG for each rectangle by the predicted compressed sizes.  While the cost
+/// models cannot tell the candidates apart, a band of the rectangle is trial-compressed with each of them, and the
+/// sizes teach the models to correct the estimates of `TightAdaptiveEncoder`.  JPEG is a candidate only if the
+/// client has asked for a quality level.
+pub struct TightTrialEncoder {
+    adaptive: TightAdaptiveEncoder,
+    jpeg: TightJpegEncoder,
+    models: [CostModel; 4],
+    trial: ZlibStream,
+    buffer: Vec<u8>,
+    count: usize,
+    jpeg_allowed: bool,
+}
+
+impl TightTrialEncoder {
+    const RAW: usize = 0;
+    const GRADIENT: usize = 1;
+    const PALETTE: usize = 2;
+    const JPEG: usize = 3;
+
+    const MIN_TRIAL_PIXELS: usize = 1024;
+    const TRIAL_PIXELS: usize = 8192;
+    // every n-th rectangle is trial-compressed anyway so that the models follow the content.
+    const EXPLORE_INTERVAL: usize = 64;
+    // in standard deviations of the log of the ratio.
+    const MARGIN: f64 = 1.0;
+
+    // the candidate of the smallest predicted size, if the models are confident of it.
+    fn predict(&self, estimates: &[Option<f64>; 4]) -> Option<usize> {
+        let mut predictions = Vec::with_capacity(4);
+        for (i, e) in estimates.iter().enumerate() {
+            if let Some(e) = *e {
+                if !self.models[i].ready() {
+                    return None;
+                }
+                predictions.push((self.models[i].predict(e), i));
+            }
+        }
+        predictions.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(cmp::Ordering::Equal));
+
+        let (p1, i1) = predictions[0];
+        match predictions.get(1) {
+            Some(&(p2, i2)) if p2 - p1 <= Self::MARGIN * f64::sqrt(self.models[i1].var + self.models[i2].var) => None,
+            _ => Some(i1),
+        }
+    }
+
+    // compresses a band in the middle of the rectangle with each candidate, and returns the smallest one.  The
+    // sizes are scaled to the whole rectangle and taught to the models.
+    fn trial(&mut self, estimates: &[Option<f64>; 4], screen: &[u32], stride: usize, w: usize, h: usize) -> usize {
+        let rows = cmp::min(h, cmp::max(1, Self::TRIAL_PIXELS / w));
+        let y0 = (h - rows) / 2;
+        let scale = h as f64 / rows as f64;
+
+        let mut best = (f64::INFINITY, Self::GRADIENT);
+        for (method, estimate) in estimates.iter().enumerate() {
+            let estimate = match *estimate {
+                Some(e) => e,
+                None => continue,
+            };
+
+            self.buffer.clear();
+            let mut overhead = 0.0;
+            let ok = match method {
+                Self::RAW => {
+                    self.trial.reset();
+                    let src = &self.adaptive.buffer_raw[3 * w * y0..3 * w * (y0 + rows)];
+                    self.trial.compress(src, &mut self.buffer)
+                }
+                Self::GRADIENT => {
+                    self.trial.reset();
+                    let src = &self.adaptive.buffer_lin[3 * w * y0..3 * w * (y0 + rows)];
+                    self.trial.compress(src, &mut self.buffer)
+                }
+                Self::PALETTE => {
+                    let palette = &self.adaptive.palette;
+                    palette.indices(&mut self.adaptive.buffer_pal, screen, stride, w, h);
+                    let row = if palette.len() <= 2 { (w + 7) / 8 } else { w };
+                    overhead = (3 * palette.len()) as f64;
+                    self.trial.reset();
+                    let src = &self.adaptive.buffer_pal[row * y0..row * (y0 + rows)];
+                    self.trial.compress(src, &mut self.buffer)
+                }
+                _ => {
+                    let jpeg = &mut self.jpeg;
+                    jpeg.compressor.compress(
+                        &mut self.buffer,
+                        &screen[stride * y0..],
+                        stride,
+                        w,
+                        rows,
+                        jpeg.quality,
+                        jpeg.subsampling,
+                    );
+                    true
+                }
+            };
+            if !ok {
+                continue;
+            }
+
+            let size = self.buffer.len() as f64 * scale + overhead;
+            self.models[method].update(estimate, size);
+            if size < best.0 {
+                best = (size, method);
+            }
+        }
+        best.1
+    }
+}
+
+impl Encoder for TightTrialEncoder {
+    fn new() -> Self {
+        TightTrialEncoder {
+            adaptive: TightAdaptiveEncoder::new(),
+            jpeg: TightJpegEncoder::new(),
+            models: [CostModel::new(), CostModel::new(), CostModel::new(), CostModel::new()],
+            trial: ZlibStream::new(),
+            buffer: Vec::new(),
+            count: 0,
+            jpeg_allowed: false,
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits::TIGHT
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.adaptive.set_options(options);
+        self.jpeg.set_options(options);
+        self.trial.set_level(options.compress_level);
+        self.jpeg_allowed = options.quality.is_some();
+    }
+
+    fn max_workers(&self) -> usize {
+        self.adaptive.max_workers()
+    }
+
+    fn pin(&mut self, i: usize) {
+        self.adaptive.pin(i);
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        // not worth the trials.
+        if w * h < Self::MIN_TRIAL_PIXELS {
+            self.adaptive.encode(out, screen, stride, w, h);
+            return;
+        }
+        if let Some(c) = TightSolid::color(screen, stride, w, h) {
+            TightSolid::fill(out, TIGHT, c);
+            return;
+        }
+
+        // the estimated sizes before zlib are the features of the models.
+        let (raw_ratio, lin_ratio) = self.adaptive.filter(screen, stride, w, h);
+        let len = (3 * w * h) as f64;
+        let mut estimates = [None; 4];
+        estimates[Self::RAW] = Some(raw_ratio * len);
+        estimates[Self::GRADIENT] = Some(lin_ratio * len);
+        let indexed = self.adaptive.palette.build(screen, stride, w, h, 256);
+        if indexed && self.adaptive.palette.len() >= 2 {
+            estimates[Self::PALETTE] = Some(self.adaptive.palette_size(raw_ratio, w * h));
+        }
+        if !indexed && self.jpeg_allowed {
+            estimates[Self::JPEG] = Some(lin_ratio * len);
+        }
+        for e in estimates.iter_mut().flatten() {
+            *e = f64::max(*e, 1.0);
+        }
+
+        self.count = self.count.wrapping_add(1);
+        let predicted = match self.predict(&estimates) {
+            Some(m) if self.count % Self::EXPLORE_INTERVAL != 0 => Some(m),
+            _ => None,
+        };
+        let method = match predicted {
+            Some(m) => m,
+            None => self.trial(&estimates, screen, stride, w, h),
+        };
+
+        match method {
+            Self::RAW => self.adaptive.compress_raw(out),
+            Self::GRADIENT => self.adaptive.compress_gradient(out),
+            Self::PALETTE => {
+                // the trials have built the indices.
+                if predicted.is_some() {
+                    let adaptive = &mut self.adaptive;
+                    adaptive.palette.indices(&mut adaptive.buffer_pal, screen, stride, w, h);
+                }
+                self.adaptive.compress_palette(out);
+            }
+            _ => self.jpeg.encode(out, screen, stride, w, h),
+        }
+    }
+
+    fn take_lossy(&mut self) -> bool {
+        self.jpeg.take_lossy()
+    }
+
+    fn refine_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        self.adaptive.encode_rect(out, screen, stride, x, y, w, h)
+    }
+
+    fn encode_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        TightSolid::encode_rect(self, out, TIGHT, screen, stride, x, y, w, h)
+    }
+}
+
+// the tiles of ZRLE and TRLE: raw, solid, packed palette, plain RLE or palette RLE, whichever is the smallest.
+struct RleTiles {
+    buffer: Vec<u8>,
+    palette: TightPalette,
+    runs: Vec<(u32, usize)>,
+    format: PixelFormat,
+}
+
+impl RleTiles {
+    fn new() -> Self {
+        RleTiles {
+            buffer: Vec::new(),
+            palette: TightPalette::new(),
+            runs: Vec::new(),
+            format: PixelFormat::SERVER,
+        }
+    }
+
+    fn encode(&mut self, screen: &[u32], stride: usize, w: usize, h: usize, tile: usize) {
+        self.buffer.clear();
+        for ty in (0..h).step_by(tile) {
+            for tx in (0..w).step_by(tile) {
+                let tw = cmp::min(tile, w - tx);
+                let th = cmp::min(tile, h - ty);
+                self.tile(&screen[stride * ty + tx..], stride, tw, th);
+            }
+        }
+    }
+
+    fn tile(&mut self, screen: &[u32], stride: usize, w: usize, h: usize) {
+        let n_colors = if self.palette.build(screen, stride, w, h, 127) {
+            self.palette.len()
+        } else {
+            usize::MAX
+        };
+        if n_colors == 1 {
+            self.buffer.push(1);
+            self.format.cpixel(&mut self.buffer, screen[0]);
+            return;
+        }
+
+        // runs continue across the rows.
+        self.runs.clear();
+        for sy in (0..stride * h).step_by(stride) {
+            for &c in screen[sy..sy + w].iter() {
+                let c = c & 0x00ffffff;
+                match self.runs.last_mut() {
+                    Some(run) if run.0 == c => run.1 += 1,
+                    _ => self.runs.push((c, 1)),
+                }
+            }
+        }
+        let run_bytes: usize = self.runs.iter().map(|&(_, n)| (n - 1) / 255 + 1).sum();
+        let long_run_bytes: usize = self
+            .runs
+            .iter()
+            .filter(|&&(_, n)| n > 1)
+            .map(|&(_, n)| (n - 1) / 255 + 1)
+            .sum();
+
+        let bits = match n_colors {
+            2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 0,
+        };
+        let cpixel_len = self.format.cpixel_len();
+        let raw_size = cpixel_len * w * h;
+        let rle_size = cpixel_len * self.runs.len() + run_bytes;
+        let packed_size = if bits > 0 {
+            cpixel_len * n_colors + h * ((bits * w + 7) / 8)
+        } else {
+            usize::MAX
+        };
+        let pal_rle_size = if n_colors <= 127 {
+            cpixel_len * n_colors + self.runs.len() + long_run_bytes
+        } else {
+            usize::MAX
+        };
+
+        let min_size = cmp::min(cmp::min(raw_size, rle_size), cmp::min(packed_size, pal_rle_size));
+        if min_size == packed_size {
+            self.buffer.push(n_colors as u8);
+            self.write_palette();
+            for sy in (0..stride * h).step_by(stride) {
+                let mut byte = 0;
+                let mut n_bits = 0;
+                for &c in screen[sy..sy + w].iter() {
+                    byte = (byte << bits) | self.palette.index(c);
+                    n_bits += bits;
+                    if n_bits == 8 {
+                        self.buffer.push(byte);
+                        byte = 0;
+                        n_bits = 0;
+                    }
+                }
+                if n_bits > 0 {
+                    self.buffer.push(byte << (8 - n_bits));
+                }
+            }
+        } else if min_size == pal_rle_size {
+            self.buffer.push(128 + n_colors as u8);
+            self.write_palette();
+            for &(c, n) in self.runs.iter() {
+                let index = self.palette.index(c);
+                if n == 1 {
+                    self.buffer.push(index);
+                } else {
+                    self.buffer.push(index | 128);
+                    Self::run_length(&mut self.buffer, n);
+                }
+            }
+        } else if min_size == rle_size {
+            self.buffer.push(128);
+            for &(c, n) in self.runs.iter() {
+                self.format.cpixel(&mut self.buffer, c);
+                Self::run_length(&mut self.buffer, n);
+            }
+        } else {
+            self.buffer.push(0);
+            for sy in (0..stride * h).step_by(stride) {
+                for &c in screen[sy..sy + w].iter() {
+                    self.format.cpixel(&mut self.buffer, c);
+                }
+            }
+        }
+    }
+
+    fn write_palette(&mut self) {
+        for &c in self.palette.colors().iter() {
+            self.format.cpixel(&mut self.buffer, c);
+        }
+    }
+
+    // 255, 255, ..., (n - 1) % 255.
+    fn run_length(buffer: &mut Vec<u8>, n: usize) {
+        let mut n = n - 1;
+        while n >= 255 {
+            buffer.push(255);
+            n -= 255;
+        }
+        buffer.push(n as u8);
+    }
+}
+
+/// ZRLE: 64x64 RLE tiles through a persistent zlib stream.
+pub struct ZrleEncoder {
+    tiles: RleTiles,
+    zlib: ZlibStream,
+}
+
+impl ZrleEncoder {
+    const TILE: usize = 64;
+}
+
+impl Encoder for ZrleEncoder {
+    fn new() -> Self {
+        ZrleEncoder {
+            tiles: RleTiles::new(),
+            zlib: ZlibStream::new(),
+        }
+    }
+
+    // a u32 length, and CPIXEL with the tile headers.
+    fn limits(&self) -> Limits {
+        Limits {
+            max_bytes: u32::MAX as usize,
+            max_bytes_per_pixel: 4,
+            ..Limits::PROTOCOL
+        }
+    }
+
+    fn supports_format(&self, format: &PixelFormat) -> bool {
+        format.is_true_colour()
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.zlib.set_level(options.compress_level);
+        self.tiles.format = options.format;
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        self.tiles.encode(screen, stride, w, h, Self::TILE);
+        out.extend(&[0, 0, 0, 16]); // encoding type: ZRLE.
+        self.zlib.write(&self.tiles.buffer, out);
+    }
+}
+
+/// Zlib: the raw pixels in the pixel format of the client through a persistent zlib stream.
+pub struct ZlibEncoder {
+    buffer: Vec<u8>,
+    zlib: ZlibStream,
+    format: PixelFormat,
+}
+
+impl Encoder for ZlibEncoder {
+    fn new() -> Self {
+        ZlibEncoder {
+            buffer: Vec::new(),
+            zlib: ZlibStream::new(),
+            format: PixelFormat::SERVER,
+        }
+    }
+
+    // a u32 length, and a pixel with the overhead of deflate.
+    fn limits(&self) -> Limits {
+        Limits {
+            max_bytes: u32::MAX as usize,
+            max_bytes_per_pixel: 5,
+            ..Limits::PROTOCOL
+        }
+    }
+
+    fn supports_format(&self, format: &PixelFormat) -> bool {
+        format.is_true_colour()
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.zlib.set_level(options.compress_level);
+        self.format = options.format;
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        self.buffer.clear();
+        RawEncoder::pixels(&mut self.buffer, &self.format, screen, stride, w, h);
+        out.extend(&[0, 0, 0, 6]); // encoding type: Zlib.
+        self.zlib.write(&self.buffer, out);
+    }
+}
+
+/// TRLE: 16x16 RLE tiles of ZRLE without zlib, for clients which lack it.
+pub struct TrleEncoder {
+    tiles: RleTiles,
+}
+
+impl TrleEncoder {
+    const TILE: usize = 16;
+}
+
+impl Encoder for TrleEncoder {
+    fn new() -> Self {
+        TrleEncoder { tiles: RleTiles::new() }
+    }
+
+    fn supports_format(&self, format: &PixelFormat) -> bool {
+        format.is_true_colour()
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.tiles.format = options.format;
+    }
+
+    fn max_workers(&self) -> usize {
+        usize::MAX
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        self.tiles.encode(screen, stride, w, h, Self::TILE);
+        out.extend(&[0, 0, 0, 15]); // encoding type: TRLE.
+        out.extend_from_slice(&self.tiles.buffer);
+    }
+}
+
+/// Hextile: 16x16 tiles of a background and subrectangles of foreground colours, or raw pixels.
+pub struct HextileEncoder {
+    covered: Vec<bool>,
+    subrects: Vec<u8>,
+    palette: TightPalette,
+    format: PixelFormat,
+}
+
+impl HextileEncoder {
+    const TILE: usize = 16;
+    const RAW: u8 = 1;
+    const BACKGROUND_SPECIFIED: u8 = 2;
+    const FOREGROUND_SPECIFIED: u8 = 4;
+    const ANY_SUBRECTS: u8 = 8;
+    const SUBRECTS_COLOURED: u8 = 16;
+
+    // `bg` and `fg` are the colours inherited from the previous tile.
+    fn tile(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        w: usize,
+        h: usize,
+        bg: &mut Option<u32>,
+        fg: &mut Option<u32>,
+    ) {
+        let n_colors = if self.palette.build(screen, stride, w, h, 16) {
+            self.palette.len()
+        } else {
+            usize::MAX
+        };
+        if n_colors == usize::MAX {
+            self.raw(out, screen, stride, w, h, bg, fg);
+            return;
+        }
+
+        // the most frequent colour is the background.
+        let mut counts = [0; 16];
+        for sy in (0..stride * h).step_by(stride) {
+            for &c in screen[sy..sy + w].iter() {
+                counts[self.palette.index(c) as usize] += 1;
+            }
+        }
+        let bg_index = (0..n_colors).max_by_key(|&i| counts[i]).unwrap();
+        let tile_bg = self.palette.colors()[bg_index];
+        let coloured = n_colors > 2;
+
+        // greedy subrectangles; each is extended to the right first, then downwards.
+        self.subrects.clear();
+        self.covered.clear();
+        self.covered.resize(w * h, false);
+        let mut n_subrects = 0;
+        let mut tile_fg = None;
+        for y in 0..h {
+            for x in 0..w {
+                let c = screen[stride * y + x] & 0x00ffffff;
+                if c == tile_bg || self.covered[w * y + x] {
+                    continue;
+                }
+                let same = |x: usize, y: usize| screen[stride * y + x] & 0x00ffffff == c;
+                let mut x1 = x + 1;
+                while x1 < w && same(x1, y) && !self.covered[w * y + x1] {
+                    x1 += 1;
+                }
+                let mut y1 = y + 1;
+                while y1 < h && (x..x1).all(|x| same(x, y1) && !self.covered[w * y1 + x]) {
+                    y1 += 1;
+                }
+                for cy in y..y1 {
+                    for cx in x..x1 {
+                        self.covered[w * cy + cx] = true;
+                    }
+                }
+                if coloured {
+                    self.format.pixel(&mut self.subrects, c);
+                }
+                self.subrects.push(((x << 4) | y) as u8);
+                self.subrects.push((((x1 - x - 1) << 4) | (y1 - y - 1)) as u8);
+                n_subrects += 1;
+                tile_fg = Some(c);
+            }
+        }
+
+        let pixel_len = self.format.bits_per_pixel as usize / 8;
+        let size = 1 + 2 * pixel_len + 1 + self.subrects.len();
+        if n_subrects > 255 || size > 1 + pixel_len * w * h {
+            self.raw(out, screen, stride, w, h, bg, fg);
+            return;
+        }
+
+        let mut mask = 0;
+        let mask_index = out.len();
+        out.push(0);
+        if *bg != Some(tile_bg) {
+            mask |= Self::BACKGROUND_SPECIFIED;
+            self.format.pixel(out, tile_bg);
+            *bg = Some(tile_bg);
+        }
+        if n_subrects > 0 {
+            mask |= Self::ANY_SUBRECTS;
+            if coloured {
+                mask |= Self::SUBRECTS_COLOURED;
+                // the foreground is undefined after coloured subrectangles.
+                *fg = None;
+            } else if *fg != tile_fg {
+                mask |= Self::FOREGROUND_SPECIFIED;
+                self.format.pixel(out, tile_fg.unwrap());
+                *fg = tile_fg;
+            }
+            out.push(n_subrects as u8);
+            out.extend_from_slice(&self.subrects);
+        }
+        out[mask_index] = mask;
+    }
+
+    fn raw(
+        &self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        w: usize,
+        h: usize,
+        bg: &mut Option<u32>,
+        fg: &mut Option<u32>,
+    ) {
+        out.push(Self::RAW);
+        RawEncoder::pixels(out, &self.format, screen, stride, w, h);
+        // the background and the foreground are undefined after a raw tile.
+        *bg = None;
+        *fg = None;
+    }
+}
+
+impl Encoder for HextileEncoder {
+    fn new() -> Self {
+        HextileEncoder {
+            covered: Vec::new(),
+            subrects: Vec::new(),
+            palette: TightPalette::new(),
+            format: PixelFormat::SERVER,
+        }
+    }
+
+    fn supports_format(&self, format: &PixelFormat) -> bool {
+        format.is_true_colour()
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.format = options.format;
+    }
+
+    fn max_workers(&self) -> usize {
+        usize::MAX
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        out.extend(&[0, 0, 0, 5]); // encoding type: Hextile.
+        let mut bg = None;
+        let mut fg = None;
+        for ty in (0..h).step_by(Self::TILE) {
+            for tx in (0..w).step_by(Self::TILE) {
+                let tw = cmp::min(Self::TILE, w - tx);
+                let th = cmp::min(Self::TILE, h - ty);
+                self.tile(out, &screen[stride * ty + tx..], stride, tw, th, &mut bg, &mut fg);
+            }
+        }
+    }
+}
+
+/// A minimal PNG writer: 8 bit RGB with the filter chosen per row, or indexed colours.
+pub struct PngWriter {
+    data: Vec<u8>,
+    rows: [Vec<u8>; 5],
+    level: u8,
+}
+
+impl PngWriter {
+    const CRC_TABLE: [u32; 256] = Self::crc_table();
+
+    pub fn new() -> Self {
+        PngWriter {
+            data: Vec::new(),
+            rows: [Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+            level: 1,
+        }
+    }
+
+    /// zlib level, 0-9.
+    pub fn set_level(&mut self, level: u8) {
+        self.level = cmp::min(level, 9);
+    }
+
+    pub fn rgb(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        self.data.clear();
+        let mut prev = vec![0; 3 * w];
+        let mut curr = vec![0; 3 * w];
+        for sy in (0..stride * h).step_by(stride) {
+            for (dst, &c) in curr.chunks_exact_mut(3).zip(screen[sy..sy + w].iter()) {
+                dst.copy_from_slice(&[(c >> 16) as u8, (c >> 8) as u8, c as u8]);
+            }
+            self.filter_row(&curr, &prev);
+            mem::swap(&mut prev, &mut curr);
+        }
+
+        let mut header = Vec::with_capacity(13);
+        header.extend(&(w as u32).to_be_bytes());
+        header.extend(&(h as u32).to_be_bytes());
+        header.extend(&[8, 2, 0, 0, 0]); // depth, colour type: RGB, compression, filter, interlace.
+        self.write(out, &header, None);
+    }
+
+    /// `indices` are written by `TightPalette::indices()`.
+    pub fn indexed(&mut self, out: &mut Vec<u8>, colors: &[u32], indices: &[u8], w: usize, h: usize) {
+        let depth = if colors.len() <= 2 { 1 } else { 8 };
+        let row_len = (depth * w + 7) / 8;
+        self.data.clear();
+        // the filters hardly work for indices.
+        for row in indices.chunks(row_len) {
+            self.data.push(0);
+            self.data.extend_from_slice(row);
+        }
+
+        let mut header = Vec::with_capacity(13);
+        header.extend(&(w as u32).to_be_bytes());
+        header.extend(&(h as u32).to_be_bytes());
+        header.extend(&[depth as u8, 3, 0, 0, 0]); // depth, colour type: indexed, compression, filter, interlace.
+        let mut palette = Vec::with_capacity(3 * colors.len());
+        for &c in colors.iter() {
+            palette.extend(&[(c >> 16) as u8, (c >> 8) as u8, c as u8]);
+        }
+        self.write(out, &header, Some(&palette));
+    }
+
+    // chooses the filter which minimizes the sum of the absolute differences, as libpng does.
+    fn filter_row(&mut self, curr: &[u8], prev: &[u8]) {
+        for (i, row) in self.rows.iter_mut().enumerate() {
+            row.clear();
+            row.push(i as u8);
+        }
+        for x in 0..curr.len() {
+            let a = if x >= 3 { curr[x - 3] } else { 0 };
+            let b = prev[x];
+            let c = if x >= 3 { prev[x - 3] } else { 0 };
+            let p = a as i16 + b as i16 - c as i16;
+            let pa = (p - a as i16).abs();
+            let pb = (p - b as i16).abs();
+            let pc = (p - c as i16).abs();
+            let paeth = if pa <= pb && pa <= pc {
+                a
+            } else if pb <= pc {
+                b
+            } else {
+                c
+            };
+            let v = curr[x];
+            self.rows[0].push(v);
+            self.rows[1].push(v.wrapping_sub(a));
+            self.rows[2].push(v.wrapping_sub(b));
+            self.rows[3].push(v.wrapping_sub(((a as u16 + b as u16) / 2) as u8));
+            self.rows[4].push(v.wrapping_sub(paeth));
+        }
+        let cost = |row: &Vec<u8>| row[1..].iter().map(|&v| (v as i8 as i32).abs()).sum::<i32>();
+        let best = self.rows.iter().min_by_key(|row| cost(row)).unwrap();
+        self.data.extend_from_slice(best);
+    }
+
+    fn write(&self, out: &mut Vec<u8>, header: &[u8], palette: Option<&[u8]>) {
+        let idat = deflate::compress_to_vec_zlib(&self.data, self.level);
+        out.extend(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']);
+        Self::chunk(out, b"IHDR", header);
+        if let Some(palette) = palette {
+            Self::chunk(out, b"PLTE", palette);
+        }
+        Self::chunk(out, b"IDAT", &idat);
+        Self::chunk(out, b"IEND", &[]);
+    }
+
+    fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+        out.extend(&(data.len() as u32).to_be_bytes());
+        let start = out.len();
+        out.extend(kind);
+        out.extend_from_slice(data);
+        let crc = out[start..].iter().fold(!0, |crc, &v| {
+            Self::CRC_TABLE[((crc ^ v as u32) & 0xff) as usize] ^ (crc >> 8)
+        });
+        out.extend(&(!crc).to_be_bytes());
+    }
+
+    const fn crc_table() -> [u32; 256] {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    }
+}
+
+/// TightPNG, which is preferred by noVNC: the fill, JPEG and PNG instead of the zlib streams.
+pub struct TightPngEncoder {
+    jpeg: TightJpegEncoder,
+    png: PngWriter,
+    palette: TightPalette,
+    indices: Vec<u8>,
+}
+
+impl TightPngEncoder {
+    // `indexed` if the palette has been built for the rectangle.
+    fn encode_png(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize, indexed: bool) {
+        out.extend(&TIGHT_PNG.to_be_bytes());
+        out.push(0b1010_0000); // compression control: PNG.
+        let len_index = out.len();
+        out.extend(&[0, 0, 0]);
+
+        let png_index = out.len();
+        if indexed {
+            self.palette.indices(&mut self.indices, screen, stride, w, h);
+            self.png.indexed(out, self.palette.colors(), &self.indices, w, h);
+        } else {
+            self.png.rgb(out, screen, stride, w, h);
+        }
+
+        let png_len = out.len() - png_index;
+        assert!(png_len < 1 << 22);
+        out[len_index + 0] = 0x80 | (png_len & 0x7f) as u8;
+        out[len_index + 1] = 0x80 | ((png_len >> 7) & 0x7f) as u8;
+        out[len_index + 2] = (png_len >> 14) as u8;
+    }
+}
+
+impl Encoder for TightPngEncoder {
+    fn new() -> Self {
+        TightPngEncoder {
+            jpeg: TightJpegEncoder::new(),
+            png: PngWriter::new(),
+            palette: TightPalette::new(),
+            indices: Vec::new(),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits::TIGHT
+    }
+
+    fn set_options(&mut self, options: &Options) {
+        self.jpeg.set_options(options);
+        self.png.set_level(options.compress_level);
+    }
+
+    fn max_workers(&self) -> usize {
+        usize::MAX
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        if let Some(c) = TightSolid::color(screen, stride, w, h) {
+            TightSolid::fill(out, TIGHT_PNG, c);
+            return;
+        }
+        if TightMixedEncoder::is_photographic(&mut self.palette, screen, stride, w, h) {
+            self.jpeg.encode_as(out, TIGHT_PNG, screen, stride, w, h);
+            return;
+        }
+        let indexed = self.palette.build(screen, stride, w, h, 256);
+        self.encode_png(out, screen, stride, w, h, indexed);
+    }
+
+    fn take_lossy(&mut self) -> bool {
+        self.jpeg.take_lossy()
+    }
+
+    fn refine_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        write_rect_header(out, x, y, w, h);
+        let indexed = self.palette.build(screen, stride, w, h, 256);
+        self.encode_png(out, screen, stride, w, h, indexed);
+        1
+    }
+
+    fn encode_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        TightSolid::encode_rect(self, out, TIGHT_PNG, screen, stride, x, y, w, h)
+    }
+}
+
+#[cfg(not(feature = "rust-jpeg"))]
+extern {
+    fn jpeg_compressor_create() -> *mut ffi::c_void;
+    fn jpeg_compressor_destroy(this: *mut ffi::c_void);
+    fn jpeg_compressor_compress(
+        this: *mut ffi::c_void,
+        dst: *mut *const u8,
+        src: *const u32,
+        stride: usize,
+        w: usize,
+        h: usize,
+        quality: i32,
+        subsampling: i32,
+    ) -> usize;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: u32 = 0x112233;
+    const B: u32 = 0x445566;
+    const C: u32 = 0x778899;
+
+    fn encode<E: Encoder>(encoder: &mut E, screen: &[u32], w: usize, h: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        encoder.encode(&mut out, screen, w, w, h);
+        out
+    }
+
+    // rows of `a` above rows of `b`.
+    fn halves(w: usize, h: usize, a: u32, b: u32) -> Vec<u32> {
+        (0..w * h).map(|i| if i < w * h / 2 { a } else { b }).collect()
+    }
+
+    fn inflate(state: &mut miniz_oxide::inflate::stream::InflateState, zlib: &[u8]) -> Vec<u8> {
+        let mut out = vec![0; 1 << 16];
+        let result = miniz_oxide::inflate::stream::inflate(state, zlib, &mut out, miniz_oxide::MZFlush::Sync);
+        assert_eq!(result.bytes_consumed, zlib.len());
+        out.truncate(result.bytes_written);
+        out
+    }
+
+    #[test]
+    fn cpixel() {
+        let cpixel = |format: PixelFormat, c: u32| {
+            let mut out = Vec::new();
+            format.cpixel(&mut out, c);
+            assert_eq!(out.len(), format.cpixel_len());
+            out
+        };
+        // the lower 3 bytes, in the order of the byte order.
+        assert_eq!(cpixel(PixelFormat::SERVER, A), &[0x11, 0x22, 0x33]);
+        let bgr = PixelFormat {
+            red_shift: 16,
+            blue_shift: 0,
+            ..PixelFormat::SERVER
+        };
+        assert_eq!(cpixel(bgr, A), &[0x33, 0x22, 0x11]);
+        let be = PixelFormat {
+            big_endian: true,
+            ..PixelFormat::SERVER
+        };
+        assert_eq!(cpixel(be, A), &[0x33, 0x22, 0x11]);
+        // the upper 3 bytes.
+        let high = PixelFormat {
+            red_shift: 8,
+            green_shift: 16,
+            blue_shift: 24,
+            ..PixelFormat::SERVER
+        };
+        assert_eq!(cpixel(high, A), &[0x11, 0x22, 0x33]);
+        // PIXEL if the colours span 4 bytes or the pixel is smaller.
+        let wide = PixelFormat {
+            blue_shift: 20,
+            ..PixelFormat::SERVER
+        };
+        assert_eq!(cpixel(wide, 0x0000ff), &[0, 0, 0xf0, 0x0f]);
+        let rgb565 = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+            ..PixelFormat::SERVER
+        };
+        assert_eq!(cpixel(rgb565, 0xff0000), &[0xf8, 0x00]);
+        assert_eq!(cpixel(rgb565, 0x00ff00), &[0x07, 0xe0]);
+        assert_eq!(cpixel(rgb565, 0x808080), &[0x84, 0x10]);
+    }
+
+    #[test]
+    fn zrle() {
+        let mut encoder = ZrleEncoder::new();
+        let mut state = miniz_oxide::inflate::stream::InflateState::new_boxed(miniz_oxide::DataFormat::Zlib);
+
+        // a 64 x 64 tile and a 8 x 64 one, each of two runs of the plain RLE.
+        let (w, h) = (72, 64);
+        let out = encode(&mut encoder, &halves(w, h, A, B), w, h);
+        assert_eq!(&out[..4], &[0, 0, 0, 16]);
+        let len = u32::from_be_bytes([out[4], out[5], out[6], out[7]]) as usize;
+        assert_eq!(out.len(), 8 + len);
+        let mut expected = vec![128];
+        for &c in [A, B].iter() {
+            PixelFormat::SERVER.cpixel(&mut expected, c);
+            // 32 x 64 - 1 = 8 x 255 + 7.
+            expected.extend(&[255; 8]);
+            expected.push(7);
+        }
+        let mut second = vec![128];
+        for &c in [A, B].iter() {
+            PixelFormat::SERVER.cpixel(&mut second, c);
+            // 4 x 64 - 1 = 255 + 0.
+            second.extend(&[255, 0]);
+        }
+        expected.extend(second);
+        assert_eq!(inflate(&mut state, &out[8..]), expected);
+
+        // the stream continues with the next rectangle, here a solid tile of 16 bit pixels.
+        let mut options = Options::default();
+        options.format = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+            ..PixelFormat::SERVER
+        };
+        encoder.set_options(&options);
+        let out = encode(&mut encoder, &[0x0000ff; 16], 4, 4);
+        assert_eq!(inflate(&mut state, &out[8..]), &[1, 0x1f, 0x00]);
+    }
+
+    fn cpixels(colors: &[u32]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for &c in colors.iter() {
+            PixelFormat::SERVER.cpixel(&mut out, c);
+        }
+        out
+    }
+
+    fn pixels(colors: &[u32]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for &c in colors.iter() {
+            PixelFormat::SERVER.pixel(&mut out, c);
+        }
+        out
+    }
+
+    #[test]
+    fn trle_subencodings() {
+        let mut encoder = TrleEncoder::new();
+        let trle = |encoder: &mut TrleEncoder, screen: &[u32], w: usize, h: usize| {
+            let out = encode(encoder, screen, w, h);
+            assert_eq!(&out[..4], &[0, 0, 0, 15]);
+            out[4..].to_vec()
+        };
+
+        // solid, in a 16 x 1 tile and a 4 x 1 one.
+        let mut expected = vec![1];
+        expected.extend(cpixels(&[A]));
+        expected.extend(expected.clone());
+        assert_eq!(trle(&mut encoder, &[A; 20], 20, 1), expected);
+
+        // packed palette of 1 bit, MSB first and each row padded.
+        let mut expected = vec![2];
+        expected.extend(cpixels(&[A, B]));
+        expected.extend(&[0b0101_0000, 0b1010_0000]);
+        assert_eq!(trle(&mut encoder, &[A, B, A, B, B, A, B, A], 4, 2), expected);
+
+        // packed palette of 2 bits.
+        let mut expected = vec![3];
+        expected.extend(cpixels(&[A, B, C]));
+        expected.extend(&[0b0001_1000]);
+        assert_eq!(trle(&mut encoder, &[A, B, C, A], 4, 1), expected);
+
+        // plain RLE of two runs of 128 pixels.
+        let mut expected = vec![128];
+        expected.extend(cpixels(&[A]));
+        expected.push(127);
+        expected.extend(cpixels(&[B]));
+        expected.push(127);
+        assert_eq!(trle(&mut encoder, &halves(16, 16, A, B), 16, 16), expected);
+
+        // palette RLE of 17 colours, too many to pack, in runs of 1 and a run of 3.
+        let colors: Vec<u32> = (0..17).map(|i| 0x010101 * i).collect();
+        let mut screen: Vec<u32> = (0..64).map(|i| colors[i % 17]).collect();
+        screen[62] = screen[61];
+        screen[63] = screen[61];
+        let mut expected = vec![128 + 17];
+        expected.extend(cpixels(&colors));
+        expected.extend((0..61).map(|i| (i % 17) as u8));
+        expected.extend(&[(61 % 17) as u8 | 128, 2]);
+        assert_eq!(trle(&mut encoder, &screen, 16, 4), expected);
+
+        // raw if every pixel differs.
+        let screen: Vec<u32> = (0..16).map(|i| 0x030201 * i).collect();
+        let mut expected = vec![0];
+        expected.extend(cpixels(&screen));
+        assert_eq!(trle(&mut encoder, &screen, 4, 4), expected);
+    }
+
+    #[test]
+    fn hextile_subrects() {
+        const BACKGROUND: u8 = HextileEncoder::BACKGROUND_SPECIFIED;
+        const FOREGROUND: u8 = HextileEncoder::FOREGROUND_SPECIFIED;
+        const SUBRECTS: u8 = HextileEncoder::ANY_SUBRECTS;
+        const COLOURED: u8 = HextileEncoder::SUBRECTS_COLOURED;
+        let mut encoder = HextileEncoder::new();
+        let hextile = |encoder: &mut HextileEncoder, screen: &[u32], w: usize, h: usize| {
+            let out = encode(encoder, screen, w, h);
+            assert_eq!(&out[..4], &[0, 0, 0, 5]);
+            out[4..].to_vec()
+        };
+
+        // the background of the second tile is inherited.
+        let mut expected = vec![BACKGROUND];
+        expected.extend(pixels(&[A]));
+        expected.push(0);
+        assert_eq!(hextile(&mut encoder, &[A; 20], 20, 1), expected);
+
+        // a 2 x 2 foreground subrectangle at (1, 1), and the same in the next tile without the foreground.
+        let mut screen = vec![A; 20 * 4];
+        for &(x, y) in [(1, 1), (2, 1), (1, 2), (2, 2), (17, 1), (18, 1), (17, 2), (18, 2)].iter() {
+            screen[20 * y + x] = B;
+        }
+        let mut expected = vec![BACKGROUND | FOREGROUND | SUBRECTS];
+        expected.extend(pixels(&[A, B]));
+        expected.extend(&[1, 0x11, 0x11, SUBRECTS, 1, 0x11, 0x11]);
+        assert_eq!(hextile(&mut encoder, &screen, 20, 4), expected);
+
+        // coloured subrectangles, extended to the right first.
+        let mut screen = vec![A; 16];
+        screen[0] = B;
+        screen[1] = B;
+        screen[15] = C;
+        let mut expected = vec![BACKGROUND | SUBRECTS | COLOURED];
+        expected.extend(pixels(&[A]));
+        expected.push(2);
+        expected.extend(pixels(&[B]));
+        expected.extend(&[0x00, 0x10]);
+        expected.extend(pixels(&[C]));
+        expected.extend(&[0x33, 0x00]);
+        assert_eq!(hextile(&mut encoder, &screen, 4, 4), expected);
+
+        // raw if the subrectangles are larger, after which the background is specified again.
+        let mut screen = vec![A; 20 * 4];
+        for y in 0..4 {
+            for x in 0..4 {
+                screen[20 * y + x] = 0x030201 * (4 * y + x) as u32;
+            }
+        }
+        let mut expected = vec![HextileEncoder::RAW];
+        for y in 0..4 {
+            expected.extend(pixels(&screen[20 * y..20 * y + 16]));
+        }
+        expected.push(BACKGROUND);
+        expected.extend(pixels(&[A]));
+        assert_eq!(hextile(&mut encoder, &screen, 20, 4), expected);
+    }
+
+    // the chunks of a PNG, checking the signature and the CRCs.
+    fn png_chunks(png: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let (body, crc) = (&rest[4..8 + len], &rest[8 + len..12 + len]);
+            let expected = !body.iter().fold(!0u32, |mut crc, &v| {
+                crc ^= v as u32;
+                for _ in 0..8 {
+                    crc = if crc & 1 != 0 {
+                        0xedb8_8320 ^ (crc >> 1)
+                    } else {
+                        crc >> 1
+                    };
+                }
+                crc
+            });
+            assert_eq!(crc, &expected.to_be_bytes());
+            chunks.push(([body[0], body[1], body[2], body[3]], &body[4..]));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    // the RGB pixels of a PNG.
+    fn png_decode(png: &[u8]) -> Vec<u32> {
+        let mut decoder = png::Decoder::new(io::Cursor::new(png));
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        buf[..info.buffer_size()]
+            .chunks_exact(3)
+            .map(|c| (c[0] as u32) << 16 | (c[1] as u32) << 8 | c[2] as u32)
+            .collect()
+    }
+
+    #[test]
+    fn png_rgb() {
+        // a gradient, a flat area and noise, so that the rows take different filters.
+        let (w, h, stride) = (24, 16, 30usize);
+        let screen: Vec<u32> = (0..stride * h)
+            .map(|i| match (i % stride, i / stride) {
+                (x, y) if y < 6 => (x as u32 * 10) << 16 | (y as u32 * 15) << 8 | 0x40,
+                (_, y) if y < 10 => A,
+                (x, y) => (x * 2654435761 ^ y * 40503).wrapping_mul(2246822519) as u32 >> 8,
+            })
+            .collect();
+        let mut png = Vec::new();
+        PngWriter::new().rgb(&mut png, &screen, stride, w, h);
+
+        let chunks = png_chunks(&png);
+        let kinds: Vec<_> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, &[0, 0, 0, 24, 0, 0, 0, 16, 8, 2, 0, 0, 0]);
+        // a filter byte before each row of 3 x w bytes.
+        let data = miniz_oxide::inflate::decompress_to_vec_zlib(chunks[1].1).unwrap();
+        assert_eq!(data.len(), h * (1 + 3 * w));
+        let filters: Vec<u8> = data.chunks(1 + 3 * w).map(|row| row[0]).collect();
+        assert!(filters.iter().all(|&f| f <= 4));
+        assert!(filters.iter().any(|&f| f != filters[0]));
+
+        let expected: Vec<u32> = screen.chunks(stride).flat_map(|row| row[..w].iter().copied()).collect();
+        assert_eq!(png_decode(&png), expected);
+    }
+
+    #[test]
+    fn png_indexed() {
+        // the PNG of the TightPNG rectangle.
+        let png = |screen: &[u32], w: usize, h: usize| {
+            let mut out = Vec::new();
+            let mut encoder = TightPngEncoder::new();
+            encoder.encode(&mut out, screen, w, w, h);
+            assert_eq!(&out[..5], &[0xff, 0xff, 0xfe, 0xfc, 0b1010_0000]);
+            let len = (out[5] & 0x7f) as usize | ((out[6] & 0x7f) as usize) << 7 | (out[7] as usize) << 14;
+            assert_eq!(out.len(), 8 + len);
+            out.split_off(8)
+        };
+
+        // 1 bit indices, with rows padded to whole bytes.
+        let screen = halves(13, 4, A, B);
+        let two = png(&screen, 13, 4);
+        let chunks = png_chunks(&two);
+        let kinds: Vec<_> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"PLTE", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, &[0, 0, 0, 13, 0, 0, 0, 4, 1, 3, 0, 0, 0]);
+        assert_eq!(chunks[1].1, &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        let data = miniz_oxide::inflate::decompress_to_vec_zlib(chunks[2].1).unwrap();
+        assert_eq!(data, &[0, 0, 0, 0, 0, 0, 0, 0xff, 0xf8, 0, 0xff, 0xf8]);
+        assert_eq!(png_decode(&two), screen);
+
+        // 8 bit indices.
+        let screen: Vec<u32> = (0..16 * 4).map(|i| [A, B, C][i / 3 % 3]).collect();
+        let three = png(&screen, 16, 4);
+        let chunks = png_chunks(&three);
+        assert_eq!(chunks[0].1, &[0, 0, 0, 16, 0, 0, 0, 4, 8, 3, 0, 0, 0]);
+        assert_eq!(chunks[1].1.len(), 9);
+        let data = miniz_oxide::inflate::decompress_to_vec_zlib(chunks[2].1).unwrap();
+        assert_eq!(data.len(), 4 * (1 + 16));
+        assert_eq!(png_decode(&three), screen);
+    }
 }
@@ -0,0 +1,184 @@
+use crate::capture;
+use std::*;
+
+/// A framebuffer owned by the application.  Pixels are 0x00RRGGBB, the same as scrap.  Changed areas must be
+/// reported by `mark_dirty()` and are passed to the encoder as they are, without comparing the whole screen.
+#[derive(Clone)]
+pub struct Framebuffer {
+    shared: sync::Arc<Shared>,
+}
+
+struct Shared {
+    w: usize,
+    h: usize,
+    state: sync::Mutex<State>,
+    cond: sync::Condvar,
+}
+
+struct State {
+    pixels: Vec<u32>,
+    damage: Vec<(usize, usize, usize, usize)>,
+}
+
+impl Framebuffer {
+    const MAX_DAMAGE: usize = 64;
+
+    pub fn new(w: usize, h: usize) -> Self {
+        Framebuffer {
+            shared: sync::Arc::new(Shared {
+                w,
+                h,
+                state: sync::Mutex::new(State {
+                    pixels: vec![0; w * h],
+                    damage: Vec::new(),
+                }),
+                cond: sync::Condvar::new(),
+            }),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.shared.w
+    }
+
+    pub fn height(&self) -> usize {
+        self.shared.h
+    }
+
+    /// Calls `f` with the pixels and the stride.  Call `mark_dirty()` afterwards for the modified areas.
+    pub fn write<R, F: FnOnce(&mut [u32], usize) -> R>(&self, f: F) -> R {
+        let mut state = self.shared.state.lock().unwrap();
+        f(&mut state.pixels, self.shared.w)
+    }
+
+    pub fn mark_dirty(&self, x0: usize, y0: usize, x1: usize, y1: usize) {
+        let x1 = cmp::min(x1, self.shared.w);
+        let y1 = cmp::min(y1, self.shared.h);
+        if x0 >= x1 || y0 >= y1 {
+            return;
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        state.damage.push((x0, y0, x1, y1));
+        // nobody may be consuming the damage; merge them into the bounding box rather than growing unboundedly.
+        capture::limit_damage(&mut state.damage, Self::MAX_DAMAGE);
+        self.shared.cond.notify_all();
+    }
+
+    /// Creates a capture source which starts with the whole framebuffer damaged.
+    pub fn source(&self) -> FramebufferSource {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.damage.clear();
+            state.damage.push((0, 0, self.shared.w, self.shared.h));
+        }
+        FramebufferSource {
+            framebuffer: self.clone(),
+            screen: vec![0; self.shared.w * self.shared.h],
+            damage: Vec::new(),
+        }
+    }
+}
+
+pub struct FramebufferSource {
+    framebuffer: Framebuffer,
+    screen: Vec<u32>,
+    damage: Vec<(usize, usize, usize, usize)>,
+}
+
+impl capture::Source for FramebufferSource {
+    fn width(&self) -> usize {
+        self.framebuffer.shared.w
+    }
+
+    fn height(&self) -> usize {
+        self.framebuffer.shared.h
+    }
+
+    fn frame(&mut self) -> io::Result<capture::Frame<'_>> {
+        let shared = &self.framebuffer.shared;
+        let mut state = shared.state.lock().unwrap();
+        if state.damage.is_empty() {
+            state = shared
+                .cond
+                .wait_timeout(state, time::Duration::from_secs(1) / 60)
+                .unwrap()
+                .0;
+            if state.damage.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+        }
+
+        // copy only the damaged areas so that the application is not blocked for long.
+        self.damage.clear();
+        mem::swap(&mut self.damage, &mut state.damage);
+        for &(x0, y0, x1, y1) in self.damage.iter() {
+            for y in y0..y1 {
+                let i = shared.w * y;
+                self.screen[i + x0..i + x1].copy_from_slice(&state.pixels[i + x0..i + x1]);
+            }
+        }
+
+        Ok(capture::Frame {
+            data: &self.screen,
+            width: shared.w,
+            height: shared.h,
+            stride: shared.w,
+            format: capture::PixelFormat::Bgrx,
+            timestamp: time::Instant::now(),
+            damage: capture::Damage::Exact(&self.damage),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{Damage, Source};
+
+    fn exact(frame: &capture::Frame) -> Vec<(usize, usize, usize, usize)> {
+        match frame.damage {
+            Damage::Exact(rects) => rects.to_vec(),
+            _ => panic!("not exact"),
+        }
+    }
+
+    #[test]
+    fn source_damage() {
+        let fb = Framebuffer::new(8, 4);
+        fb.write(|pixels, stride| pixels[stride + 2] = 0x123456);
+        let mut source = fb.source();
+
+        // the whole framebuffer at first.
+        {
+            let frame = source.frame().unwrap();
+            assert_eq!(exact(&frame), &[(0, 0, 8, 4)]);
+            assert_eq!(frame.data[8 + 2], 0x123456);
+        }
+        assert_eq!(source.frame().err().map(|e| e.kind()), Some(io::ErrorKind::WouldBlock));
+
+        // only the damage, clipped to the framebuffer, is copied.
+        fb.write(|pixels, stride| {
+            pixels[0] = 1;
+            pixels[stride * 3 + 7] = 2;
+        });
+        fb.mark_dirty(6, 2, 100, 100);
+        fb.mark_dirty(8, 0, 9, 4);
+        let frame = source.frame().unwrap();
+        assert_eq!(exact(&frame), &[(6, 2, 8, 4)]);
+        assert_eq!(frame.data[0], 0);
+        assert_eq!(frame.data[8 * 3 + 7], 2);
+    }
+
+    #[test]
+    fn source_damage_limit() {
+        let fb = Framebuffer::new(16, 16);
+        let mut source = fb.source();
+        source.frame().unwrap();
+        for i in 0..Framebuffer::MAX_DAMAGE + 1 {
+            fb.mark_dirty(i % 16, i / 16, i % 16 + 1, i / 16 + 1);
+        }
+        let frame = source.frame().unwrap();
+        assert_eq!(exact(&frame), &[(0, 0, 16, 5)]);
+    }
+}
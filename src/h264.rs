@@ -0,0 +1,317 @@
+use crate::encoder::{self, Encoder, Limits, TightAdaptiveEncoder};
+use openh264::encoder::{BitRate, EncoderConfig, FrameRate, UsageType};
+use openh264::formats::{RgbSliceU8, YUVBuffer};
+use openh264::OpenH264API;
+use std::*;
+
+pub const H264: i32 = 50;
+
+struct Context {
+    encoder: openh264::encoder::Encoder,
+    rect: (usize, usize, usize, usize),
+    used: u64,
+    fresh: bool,
+}
+
+/// The open H.264 encoding for the areas playing videos.  Their damage in each frame is gathered into one
+/// rectangle aligned to the grid, which has its own decoder context on the client.  The rest of the screen, and
+/// video damage which is too small or lies outside the context, is sent losslessly by `TightAdaptiveEncoder`,
+/// which also refines the H.264 rectangles once they stop changing.
+pub struct H264Encoder {
+    contexts: Vec<Context>,
+    dirty: Vec<(usize, usize, usize, usize)>,
+    rgb: Vec<u8>,
+    data: Vec<u8>,
+    fallback: TightAdaptiveEncoder,
+    size: (usize, usize),
+    bitrate: u64,
+    frame: u64,
+    reset: bool,
+    lossy: Option<(usize, usize, usize, usize)>,
+}
+
+impl H264Encoder {
+    const GRID: usize = 64;
+    const MIN_AREA: usize = 256 * 256;
+    // the same as TigerVNC.
+    const MAX_CONTEXTS: usize = 64;
+    const MAX_FRAME_RATE: f32 = 60.0;
+
+    const FLAG_RESET_CONTEXT: u32 = 1;
+    const FLAG_RESET_ALL_CONTEXTS: u32 = 2;
+
+    fn context(&mut self, rect: (usize, usize, usize, usize)) -> Option<usize> {
+        if let Some(i) = self.contexts.iter().position(|c| c.rect == rect) {
+            return Some(i);
+        }
+
+        let config = EncoderConfig::new()
+            .bitrate(BitRate::from_bps(self.bitrate as u32))
+            .max_frame_rate(FrameRate::from_hz(Self::MAX_FRAME_RATE))
+            .usage_type(UsageType::ScreenContentRealTime);
+        let encoder = match openh264::encoder::Encoder::with_api_config(OpenH264API::from_source(), config) {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("h264: {}", e);
+                return None;
+            }
+        };
+        if self.contexts.len() >= Self::MAX_CONTEXTS {
+            let (i, _) = self.contexts.iter().enumerate().min_by_key(|(_, c)| c.used)?;
+            self.contexts.swap_remove(i);
+        }
+        self.contexts.push(Context {
+            encoder,
+            rect,
+            used: 0,
+            fresh: true,
+        });
+        Some(self.contexts.len() - 1)
+    }
+
+    // the bounding box of the damage, aligned to the grid and rounded down to even sizes for YUV 4:2:0.
+    fn context_rect(&self, w: usize, h: usize) -> Option<(usize, usize, usize, usize)> {
+        let x0 = self.dirty.iter().map(|r| r.0).min()? / Self::GRID * Self::GRID;
+        let y0 = self.dirty.iter().map(|r| r.1).min()? / Self::GRID * Self::GRID;
+        let x1 = cmp::min(
+            (self.dirty.iter().map(|r| r.2).max()? + Self::GRID - 1) / Self::GRID * Self::GRID,
+            w,
+        );
+        let y1 = cmp::min(
+            (self.dirty.iter().map(|r| r.3).max()? + Self::GRID - 1) / Self::GRID * Self::GRID,
+            h,
+        );
+        let cw = (x1 - x0) & !1;
+        let ch = (y1 - y0) & !1;
+        if cw * ch < Self::MIN_AREA {
+            None
+        } else {
+            Some((x0, y0, x0 + cw, y0 + ch))
+        }
+    }
+
+    fn encode_h264(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        (x0, y0, x1, y1): (usize, usize, usize, usize),
+    ) -> bool {
+        let (w, h) = (x1 - x0, y1 - y0);
+        self.rgb.clear();
+        self.rgb.reserve(3 * w * h);
+        for y in y0..y1 {
+            for &p in screen[stride * y + x0..stride * y + x1].iter() {
+                self.rgb.extend_from_slice(&[(p >> 16) as u8, (p >> 8) as u8, p as u8]);
+            }
+        }
+        let yuv = YUVBuffer::from_rgb_source(RgbSliceU8::new(&self.rgb, (w, h)));
+
+        // new contexts start with a key frame.
+        let reset = mem::replace(&mut self.reset, false);
+        if reset {
+            self.contexts.clear();
+        }
+        let frame = self.frame;
+        let context = match self.context((x0, y0, x1, y1)) {
+            Some(i) => &mut self.contexts[i],
+            None => return false,
+        };
+        self.data.clear();
+        match context.encoder.encode(&yuv) {
+            Ok(bitstream) => bitstream.write_vec(&mut self.data),
+            Err(e) => {
+                eprintln!("h264: {}", e);
+                return false;
+            }
+        }
+        context.used = frame;
+        let mut flags = 0;
+        if mem::replace(&mut context.fresh, false) {
+            flags |= Self::FLAG_RESET_CONTEXT;
+        }
+        if reset {
+            flags |= Self::FLAG_RESET_ALL_CONTEXTS;
+        }
+
+        encoder::write_rect_header(out, x0, y0, w, h);
+        out.extend_from_slice(&H264.to_be_bytes());
+        out.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&flags.to_be_bytes());
+        out.extend_from_slice(&self.data);
+        true
+    }
+}
+
+impl Encoder for H264Encoder {
+    fn new() -> Self {
+        H264Encoder {
+            contexts: Vec::new(),
+            dirty: Vec::new(),
+            rgb: Vec::new(),
+            data: Vec::new(),
+            fallback: TightAdaptiveEncoder::new(),
+            size: (0, 0),
+            bitrate: 8_000_000,
+            frame: 0,
+            reset: true,
+            lossy: None,
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        self.fallback.limits()
+    }
+
+    fn set_options(&mut self, options: &encoder::Options) {
+        self.fallback.set_options(options);
+        // the client may have been reconnected to another decoder.
+        self.reset = true;
+    }
+
+    fn encode(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) {
+        self.fallback.encode(out, screen, stride, w, h);
+    }
+
+    fn encode_video_rect(
+        &mut self,
+        _: &mut Vec<u8>,
+        _: &[u32],
+        _: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        self.dirty.push((x, y, x + w, y + h));
+        0
+    }
+
+    fn encode_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        self.fallback.encode_rect(out, screen, stride, x, y, w, h)
+    }
+
+    fn take_lossy_rect(&mut self) -> Option<(usize, usize, usize, usize)> {
+        self.lossy.take()
+    }
+
+    fn refine_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> usize {
+        self.fallback.encode_rect(out, screen, stride, x, y, w, h)
+    }
+
+    fn end_frame(&mut self, out: &mut Vec<u8>, screen: &[u32], stride: usize, w: usize, h: usize) -> usize {
+        if self.size != (w, h) {
+            self.size = (w, h);
+            self.contexts.clear();
+            self.reset = true;
+        }
+        self.frame += 1;
+
+        let mut n = 0;
+        let rect = self.context_rect(w, h);
+        let mut dirty = mem::take(&mut self.dirty);
+        let covered = match rect {
+            Some(r) if self.encode_h264(out, screen, stride, r) => {
+                n += 1;
+                self.lossy = Some(r);
+                r
+            }
+            _ => (0, 0, 0, 0),
+        };
+        for &(x0, y0, x1, y1) in dirty.iter() {
+            if covered.0 <= x0 && covered.1 <= y0 && x1 <= covered.2 && y1 <= covered.3 {
+                continue;
+            }
+            let screen = &screen[stride * y0 + x0..];
+            n += self.fallback.encode_rect(out, screen, stride, x0, y0, x1 - x0, y1 - y0);
+        }
+        dirty.clear();
+        self.dirty = dirty;
+        n
+    }
+
+    fn set_bitrate(&mut self, bitrate: u64) {
+        // the contexts are recreated, starting with a key frame, only for a significant change.
+        if 4 * bitrate < 3 * self.bitrate || 4 * bitrate > 5 * self.bitrate {
+            self.bitrate = bitrate;
+            self.contexts.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openh264::decoder::Decoder;
+    use openh264::formats::YUVSource;
+
+    #[test]
+    fn decode_frame() {
+        let (w, h) = (256, 256);
+        let screen: Vec<u32> = (0..w * h)
+            .map(|i| {
+                let (x, y) = (i % w, i / w);
+                (x as u32) << 16 | (y as u32) << 8 | ((x + y) / 2) as u32
+            })
+            .collect();
+        let mut encoder = H264Encoder::new();
+        let mut out = Vec::new();
+        encoder.encode_video_rect(&mut out, &screen, w, 0, 0, w, h);
+        assert_eq!(encoder.end_frame(&mut out, &screen, w, w, h), 1);
+        assert_eq!(encoder.take_lossy_rect(), Some((0, 0, w, h)));
+
+        // the rectangle, the encoding, the length of the data and the flags.
+        assert_eq!(&out[..8], &[0, 0, 0, 0, 1, 0, 1, 0]);
+        assert_eq!(&out[8..12], &H264.to_be_bytes());
+        let len = u32::from_be_bytes([out[12], out[13], out[14], out[15]]) as usize;
+        assert_eq!(out.len(), 20 + len);
+        let flags = u32::from_be_bytes([out[16], out[17], out[18], out[19]]);
+        assert_eq!(
+            flags,
+            H264Encoder::FLAG_RESET_CONTEXT | H264Encoder::FLAG_RESET_ALL_CONTEXTS
+        );
+
+        let mut decoder = Decoder::new().unwrap();
+        let mut frames = 0;
+        for nal in openh264::nal_units(&out[20..]) {
+            if let Some(yuv) = decoder.decode(nal).unwrap() {
+                assert_eq!(yuv.dimensions(), (w, h));
+                let mut rgb = vec![0; 3 * w * h];
+                yuv.write_rgb8(&mut rgb);
+                let error: u64 = screen
+                    .iter()
+                    .zip(rgb.chunks(3))
+                    .map(|(&p, c)| {
+                        let d = |v: u32, c: u8| (v as u8 as i64 - c as i64).abs() as u64;
+                        d(p >> 16, c[0]) + d(p >> 8, c[1]) + d(p, c[2])
+                    })
+                    .sum();
+                assert!(
+                    error < (8 * 3 * w * h) as u64,
+                    "mean error {}",
+                    error / (3 * w * h) as u64
+                );
+                frames += 1;
+            }
+        }
+        assert_eq!(frames, 1);
+    }
+}
@@ -0,0 +1,559 @@
+use crate::encoder::Subsampling;
+use std::*;
+
+/// A baseline JPEG encoder which writes the same stream as src/jpeg_compressor.c: JFIF, the quantization tables of
+/// libjpeg and the standard Huffman tables, which are omitted as the Tight decoders know them.
+pub struct JpegCompressor {
+    quality: u8,
+    qtables: [[u8; 64]; 2],
+    divisors: [[f32; 64]; 2],
+    dc: [HuffmanTable; 2],
+    ac: [HuffmanTable; 2],
+    // Y, Cb and Cr of an MCU, up to 16 x 16.
+    mcu: [[f32; 256]; 3],
+}
+
+impl JpegCompressor {
+    const NATURAL_ORDER: [usize; 64] = [
+        0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13, 6, 7, 14,
+        21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59, 52, 45, 38, 31, 39, 46, 53, 60,
+        61, 54, 47, 55, 62, 63,
+    ];
+
+    const LUMINANCE_QTABLE: [u16; 64] = [
+        16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56, 14, 17, 22, 29,
+        51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113, 92, 49, 64, 78, 87, 103, 121,
+        120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+    ];
+
+    const CHROMINANCE_QTABLE: [u16; 64] = [
+        17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99, 47, 66, 99, 99,
+        99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+        99, 99, 99, 99, 99, 99, 99, 99,
+    ];
+
+    const AAN_SCALES: [f32; 8] = [
+        1.0,
+        1.387039845,
+        1.306562965,
+        1.175875602,
+        1.0,
+        0.785694958,
+        0.541196100,
+        0.275899379,
+    ];
+
+    pub fn new() -> Self {
+        let mut this = JpegCompressor {
+            quality: 0,
+            qtables: [[0; 64]; 2],
+            divisors: [[0.0; 64]; 2],
+            dc: [
+                HuffmanTable::new(&HuffmanTable::DC_LUMINANCE_BITS, &HuffmanTable::DC_VALUES),
+                HuffmanTable::new(&HuffmanTable::DC_CHROMINANCE_BITS, &HuffmanTable::DC_VALUES),
+            ],
+            ac: [
+                HuffmanTable::new(&HuffmanTable::AC_LUMINANCE_BITS, &HuffmanTable::AC_LUMINANCE_VALUES),
+                HuffmanTable::new(&HuffmanTable::AC_CHROMINANCE_BITS, &HuffmanTable::AC_CHROMINANCE_VALUES),
+            ],
+            mcu: [[0.0; 256]; 3],
+        };
+        this.set_quality(93);
+        this
+    }
+
+    // the same as jpeg_set_quality(quality, TRUE).
+    fn set_quality(&mut self, quality: u8) {
+        if quality == self.quality {
+            return;
+        }
+        self.quality = quality;
+        let quality = cmp::max(1, cmp::min(100, quality as u32));
+        let scale = if quality < 50 {
+            5000 / quality
+        } else {
+            200 - 2 * quality
+        };
+        for (i, base) in [Self::LUMINANCE_QTABLE, Self::CHROMINANCE_QTABLE].iter().enumerate() {
+            for j in 0..64 {
+                let q = cmp::max(1, cmp::min(255, (base[j] as u32 * scale + 50) / 100));
+                self.qtables[i][j] = q as u8;
+                self.divisors[i][j] = 1.0 / (q as f32 * Self::AAN_SCALES[j / 8] * Self::AAN_SCALES[j % 8] * 8.0);
+            }
+        }
+    }
+
+    /// Appends the JPEG of the w x h pixels of 0x00RRGGBB.
+    pub fn compress(
+        &mut self,
+        out: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        w: usize,
+        h: usize,
+        quality: u8,
+        subsampling: Subsampling,
+    ) {
+        self.set_quality(quality);
+        let (n_comps, hs, vs) = match subsampling {
+            Subsampling::S444 => (3, 1, 1),
+            Subsampling::S422 => (3, 2, 1),
+            Subsampling::S420 => (3, 2, 2),
+            Subsampling::Gray => (1, 1, 1),
+        };
+
+        // SOI, APP0 (JFIF 1.01, no units, 1:1).
+        out.extend(&[0xff, 0xd8]);
+        out.extend(&[0xff, 0xe0, 0, 16, b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0]);
+        // DQT.
+        for i in 0..cmp::min(n_comps, 2) {
+            out.extend(&[0xff, 0xdb, 0, 67, i as u8]);
+            out.extend(Self::NATURAL_ORDER.iter().map(|&j| self.qtables[i][j]));
+        }
+        // SOF0.
+        out.extend(&[0xff, 0xc0, 0, 8 + 3 * n_comps as u8, 8]);
+        out.extend(&[(h >> 8) as u8, h as u8, (w >> 8) as u8, w as u8, n_comps as u8]);
+        for i in 0..n_comps {
+            let (hi, vi) = if i == 0 { (hs, vs) } else { (1, 1) };
+            out.extend(&[i as u8 + 1, (hi << 4 | vi) as u8, cmp::min(i, 1) as u8]);
+        }
+        // DHT is omitted.  SOS.
+        out.extend(&[0xff, 0xda, 0, 6 + 2 * n_comps as u8, n_comps as u8]);
+        for i in 0..n_comps {
+            let t = cmp::min(i, 1) as u8;
+            out.extend(&[i as u8 + 1, t << 4 | t]);
+        }
+        out.extend(&[0, 63, 0]);
+
+        let mut writer = BitWriter::new(out);
+        let mut last_dc = [0; 3];
+        let (mcu_w, mcu_h) = (8 * hs, 8 * vs);
+        for my in (0..h).step_by(mcu_h) {
+            for mx in (0..w).step_by(mcu_w) {
+                self.convert(screen, stride, w, h, mx, my, mcu_w, mcu_h, n_comps);
+                for by in 0..vs {
+                    for bx in 0..hs {
+                        let block = Self::block(&self.mcu[0], mcu_w, 8 * bx, 8 * by);
+                        self.encode_block(&mut writer, &block, 0, &mut last_dc[0]);
+                    }
+                }
+                for c in 1..n_comps {
+                    let block = Self::downsample(&self.mcu[c], mcu_w, hs, vs);
+                    self.encode_block(&mut writer, &block, 1, &mut last_dc[c]);
+                }
+            }
+        }
+        writer.flush();
+
+        // EOI.
+        out.extend(&[0xff, 0xd9]);
+    }
+
+    // converts an MCU to YCbCr, repeating the pixels on the right and the bottom edges.
+    fn convert(
+        &mut self,
+        screen: &[u32],
+        stride: usize,
+        w: usize,
+        h: usize,
+        mx: usize,
+        my: usize,
+        mcu_w: usize,
+        mcu_h: usize,
+        n_comps: usize,
+    ) {
+        for y in 0..mcu_h {
+            let row = &screen[stride * cmp::min(my + y, h - 1)..];
+            for x in 0..mcu_w {
+                let p = row[cmp::min(mx + x, w - 1)];
+                let r = ((p >> 16) & 0xff) as f32;
+                let g = ((p >> 8) & 0xff) as f32;
+                let b = (p & 0xff) as f32;
+                let i = mcu_w * y + x;
+                self.mcu[0][i] = 0.299 * r + 0.587 * g + 0.114 * b - 128.0;
+                if n_comps == 3 {
+                    self.mcu[1][i] = -0.168736 * r - 0.331264 * g + 0.5 * b;
+                    self.mcu[2][i] = 0.5 * r - 0.418688 * g - 0.081312 * b;
+                }
+            }
+        }
+    }
+
+    fn block(plane: &[f32], pitch: usize, x0: usize, y0: usize) -> [f32; 64] {
+        let mut block = [0.0; 64];
+        for y in 0..8 {
+            block[8 * y..8 * y + 8].copy_from_slice(&plane[pitch * (y0 + y) + x0..pitch * (y0 + y) + x0 + 8]);
+        }
+        block
+    }
+
+    fn downsample(plane: &[f32], pitch: usize, hs: usize, vs: usize) -> [f32; 64] {
+        let mut block = [0.0; 64];
+        let scale = 1.0 / (hs * vs) as f32;
+        for y in 0..8 {
+            for x in 0..8 {
+                let mut sum = 0.0;
+                for dy in 0..vs {
+                    for dx in 0..hs {
+                        sum += plane[pitch * (vs * y + dy) + hs * x + dx];
+                    }
+                }
+                block[8 * y + x] = sum * scale;
+            }
+        }
+        block
+    }
+
+    fn encode_block(&self, writer: &mut BitWriter, block: &[f32; 64], table: usize, last_dc: &mut i32) {
+        let mut coefs = *block;
+        Self::fdct(&mut coefs);
+        let divisors = &self.divisors[table];
+        let quantize = |k: usize| {
+            let i = Self::NATURAL_ORDER[k];
+            (coefs[i] * divisors[i]).round() as i32
+        };
+
+        let dc = quantize(0);
+        let diff = dc - *last_dc;
+        *last_dc = dc;
+        let n_bits = Self::n_bits(diff);
+        self.dc[table].write(writer, n_bits as u8);
+        writer.write_value(diff, n_bits);
+
+        let mut run = 0;
+        for k in 1..64 {
+            let v = quantize(k);
+            if v == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 {
+                self.ac[table].write(writer, 0xf0);
+                run -= 16;
+            }
+            let n_bits = Self::n_bits(v);
+            self.ac[table].write(writer, (run << 4 | n_bits) as u8);
+            writer.write_value(v, n_bits);
+            run = 0;
+        }
+        if run > 0 {
+            self.ac[table].write(writer, 0x00);
+        }
+    }
+
+    fn n_bits(v: i32) -> u32 {
+        32 - v.abs().leading_zeros()
+    }
+
+    // the AAN algorithm, the same as jfdctflt.c.  The outputs are scaled by AAN_SCALES and 8.
+    fn fdct(data: &mut [f32; 64]) {
+        fn pass(d: &mut [f32; 64], i: usize, step: usize) {
+            let at = |k: usize| i + step * k;
+            let tmp0 = d[at(0)] + d[at(7)];
+            let tmp7 = d[at(0)] - d[at(7)];
+            let tmp1 = d[at(1)] + d[at(6)];
+            let tmp6 = d[at(1)] - d[at(6)];
+            let tmp2 = d[at(2)] + d[at(5)];
+            let tmp5 = d[at(2)] - d[at(5)];
+            let tmp3 = d[at(3)] + d[at(4)];
+            let tmp4 = d[at(3)] - d[at(4)];
+
+            let tmp10 = tmp0 + tmp3;
+            let tmp13 = tmp0 - tmp3;
+            let tmp11 = tmp1 + tmp2;
+            let tmp12 = tmp1 - tmp2;
+            d[at(0)] = tmp10 + tmp11;
+            d[at(4)] = tmp10 - tmp11;
+            let z1 = (tmp12 + tmp13) * 0.707106781;
+            d[at(2)] = tmp13 + z1;
+            d[at(6)] = tmp13 - z1;
+
+            let tmp10 = tmp4 + tmp5;
+            let tmp11 = tmp5 + tmp6;
+            let tmp12 = tmp6 + tmp7;
+            let z5 = (tmp10 - tmp12) * 0.382683433;
+            let z2 = 0.541196100 * tmp10 + z5;
+            let z4 = 1.306562965 * tmp12 + z5;
+            let z3 = tmp11 * 0.707106781;
+            let z11 = tmp7 + z3;
+            let z13 = tmp7 - z3;
+            d[at(5)] = z13 + z2;
+            d[at(3)] = z13 - z2;
+            d[at(1)] = z11 + z4;
+            d[at(7)] = z11 - z4;
+        }
+
+        for y in 0..8 {
+            pass(data, 8 * y, 1);
+        }
+        for x in 0..8 {
+            pass(data, x, 8);
+        }
+    }
+}
+
+struct HuffmanTable {
+    codes: [u16; 256],
+    sizes: [u8; 256],
+}
+
+impl HuffmanTable {
+    // ITU T.81 Annex K.3.
+    const DC_LUMINANCE_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+    const DC_CHROMINANCE_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+    const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+    const AC_LUMINANCE_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+    const AC_LUMINANCE_VALUES: [u8; 162] = [
+        0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07, 0x22, 0x71,
+        0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0, 0x24, 0x33, 0x62, 0x72,
+        0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x34, 0x35, 0x36, 0x37,
+        0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59,
+        0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83,
+        0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3,
+        0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+        0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+        0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+    ];
+
+    const AC_CHROMINANCE_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+    const AC_CHROMINANCE_VALUES: [u8; 162] = [
+        0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71, 0x13, 0x22,
+        0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0, 0x15, 0x62, 0x72, 0xd1,
+        0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x35, 0x36,
+        0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58,
+        0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a,
+        0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a,
+        0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba,
+        0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+        0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+    ];
+
+    fn new(bits: &[u8; 16], values: &[u8]) -> Self {
+        let mut table = HuffmanTable {
+            codes: [0; 256],
+            sizes: [0; 256],
+        };
+        let mut code = 0;
+        let mut values = values.iter();
+        for (i, &n) in bits.iter().enumerate() {
+            for &v in values.by_ref().take(n as usize) {
+                table.codes[v as usize] = code;
+                table.sizes[v as usize] = i as u8 + 1;
+                code += 1;
+            }
+            code <<= 1;
+        }
+        table
+    }
+
+    fn write(&self, writer: &mut BitWriter, symbol: u8) {
+        writer.write(self.codes[symbol as usize] as u32, self.sizes[symbol as usize] as u32);
+    }
+}
+
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    bits: u32,
+    n_bits: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        BitWriter {
+            out,
+            bits: 0,
+            n_bits: 0,
+        }
+    }
+
+    // up to 16 bits.
+    fn write(&mut self, bits: u32, n_bits: u32) {
+        self.bits = self.bits << n_bits | (bits & ((1 << n_bits) - 1));
+        self.n_bits += n_bits;
+        while self.n_bits >= 8 {
+            self.n_bits -= 8;
+            let byte = (self.bits >> self.n_bits) as u8;
+            self.out.push(byte);
+            // byte stuffing.
+            if byte == 0xff {
+                self.out.push(0);
+            }
+        }
+    }
+
+    // the lower bits of v, or of v - 1 if negative.
+    fn write_value(&mut self, v: i32, n_bits: u32) {
+        if n_bits > 0 {
+            self.write(if v < 0 { v - 1 } else { v } as u32, n_bits);
+        }
+    }
+
+    // pads with 1s.
+    fn flush(&mut self) {
+        let n = (8 - self.n_bits % 8) % 8;
+        self.write(0x7f, n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a gradient with a soft diagonal, of a size which is not a multiple of the MCUs.
+    fn image(w: usize, h: usize) -> Vec<u32> {
+        (0..w * h)
+            .map(|i| {
+                let (x, y) = (i % w, i / w);
+                let r = 255 * x / w;
+                let g = 255 * y / h;
+                let b = if x + y < (w + h) / 2 { 64 } else { 192 };
+                (r << 16 | g << 8 | b) as u32
+            })
+            .collect()
+    }
+
+    // the markers up to SOS and the index of SOS.
+    fn markers(jpeg: &[u8]) -> (Vec<u8>, usize) {
+        assert_eq!(&jpeg[..2], &[0xff, 0xd8]);
+        let mut markers = vec![0xd8];
+        let mut i = 2;
+        loop {
+            assert_eq!(jpeg[i], 0xff);
+            markers.push(jpeg[i + 1]);
+            if jpeg[i + 1] == 0xda {
+                return (markers, i);
+            }
+            i += 2 + u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+        }
+    }
+
+    // inserts the standard Huffman tables before SOS, as the Tight decoders do.
+    fn decode(jpeg: &[u8]) -> Vec<u8> {
+        let (_, sos) = markers(jpeg);
+        let mut full = jpeg[..sos].to_vec();
+        let tables: [(u8, &[u8; 16], &[u8]); 4] = [
+            (0x00, &HuffmanTable::DC_LUMINANCE_BITS, &HuffmanTable::DC_VALUES),
+            (
+                0x10,
+                &HuffmanTable::AC_LUMINANCE_BITS,
+                &HuffmanTable::AC_LUMINANCE_VALUES,
+            ),
+            (0x01, &HuffmanTable::DC_CHROMINANCE_BITS, &HuffmanTable::DC_VALUES),
+            (
+                0x11,
+                &HuffmanTable::AC_CHROMINANCE_BITS,
+                &HuffmanTable::AC_CHROMINANCE_VALUES,
+            ),
+        ];
+        for &(id, bits, values) in tables.iter() {
+            let len = 3 + bits.len() + values.len();
+            full.extend(&[0xff, 0xc4, (len >> 8) as u8, len as u8, id]);
+            full.extend_from_slice(bits);
+            full.extend_from_slice(values);
+        }
+        full.extend_from_slice(&jpeg[sos..]);
+        zune_jpeg::JpegDecoder::new(io::Cursor::new(full)).decode().unwrap()
+    }
+
+    fn compress(screen: &[u32], w: usize, h: usize, subsampling: Subsampling) -> Vec<u8> {
+        let mut out = Vec::new();
+        JpegCompressor::new().compress(&mut out, screen, w, w, h, 93, subsampling);
+        out
+    }
+
+    #[test]
+    fn markers_and_tables() {
+        let (w, h) = (45, 30);
+        let screen = image(w, h);
+        for &(subsampling, n_comps, sampling) in [
+            (Subsampling::S444, 3, 0x11),
+            (Subsampling::S420, 3, 0x22),
+            (Subsampling::Gray, 1, 0x11),
+        ]
+        .iter()
+        {
+            let jpeg = compress(&screen, w, h, subsampling);
+            let (markers, sos) = markers(&jpeg);
+            let mut expected = vec![0xd8, 0xe0];
+            expected.extend(vec![0xdb; cmp::min(n_comps, 2)]);
+            expected.extend(&[0xc0, 0xda]);
+            assert_eq!(markers, expected);
+            assert_eq!(&jpeg[jpeg.len() - 2..], &[0xff, 0xd9]);
+
+            // JFIF 1.01, then the tables of luminance and chrominance in the zigzag order.
+            assert_eq!(&jpeg[6..11], b"JFIF\0");
+            assert_eq!(&jpeg[11..13], &[1, 1]);
+            let mut i = 20;
+            for t in 0..cmp::min(n_comps, 2) {
+                assert_eq!(&jpeg[i..i + 5], &[0xff, 0xdb, 0, 67, t as u8]);
+                let qtable = JpegCompressor::new();
+                let zigzag: Vec<u8> = JpegCompressor::NATURAL_ORDER
+                    .iter()
+                    .map(|&j| qtable.qtables[t][j])
+                    .collect();
+                assert_eq!(&jpeg[i + 5..i + 69], &zigzag[..]);
+                i += 69;
+            }
+
+            // SOF0: 8 bits, h x w, and the sampling and the table of each component.
+            assert_eq!(
+                &jpeg[i..i + 10],
+                &[
+                    0xff,
+                    0xc0,
+                    0,
+                    8 + 3 * n_comps as u8,
+                    8,
+                    0,
+                    h as u8,
+                    0,
+                    w as u8,
+                    n_comps as u8
+                ]
+            );
+            for c in 0..n_comps {
+                let s = if c == 0 { sampling } else { 0x11 };
+                assert_eq!(
+                    &jpeg[i + 10 + 3 * c..i + 13 + 3 * c],
+                    &[c as u8 + 1, s, cmp::min(c, 1) as u8]
+                );
+            }
+            assert_eq!(i + 10 + 3 * n_comps, sos);
+
+            // SOS: the Huffman tables of each component, and the full spectrum.
+            assert_eq!(jpeg[sos + 4], n_comps as u8);
+            for c in 0..n_comps {
+                let t = cmp::min(c, 1) as u8;
+                assert_eq!(&jpeg[sos + 5 + 2 * c..sos + 7 + 2 * c], &[c as u8 + 1, t << 4 | t]);
+            }
+            assert_eq!(&jpeg[sos + 5 + 2 * n_comps..sos + 8 + 2 * n_comps], &[0, 63, 0]);
+        }
+    }
+
+    #[test]
+    fn decode_within_error() {
+        let (w, h) = (45, 30);
+        let screen = image(w, h);
+        for &subsampling in [Subsampling::S444, Subsampling::S420, Subsampling::Gray].iter() {
+            let pixels = decode(&compress(&screen, w, h, subsampling));
+            let n = pixels.len() / (w * h);
+            assert_eq!(pixels.len(), n * w * h);
+            let mut error = 0;
+            for (&p, q) in screen.iter().zip(pixels.chunks(n)) {
+                let (r, g, b) = ((p >> 16) as u8 as f32, (p >> 8) as u8 as f32, p as u8 as f32);
+                let expected = match subsampling {
+                    Subsampling::Gray => vec![0.299 * r + 0.587 * g + 0.114 * b; n],
+                    _ => vec![r, g, b],
+                };
+                error += expected
+                    .iter()
+                    .zip(q)
+                    .map(|(&e, &v)| (e - v as f32).abs() as usize)
+                    .sum::<usize>();
+            }
+            let mean = error / (n * w * h);
+            assert!(mean < 4, "{:?}: mean error {}", subsampling, mean);
+        }
+    }
+}
@@ -6,6 +6,9 @@
 typedef struct jpeg_compressor_t {
 	struct jpeg_error_mgr error;
 	struct jpeg_compress_struct compress;
+	// the output buffer, which libjpeg replaces by a larger one if needed.
+	unsigned char* buffer;
+	unsigned long size;
 } jpeg_compressor_t;
 
 
@@ -17,31 +20,41 @@ jpeg_compressor_t* jpeg_compressor_create() {
 	jpeg_compressor_t* self = malloc(sizeof(jpeg_compressor_t));
 	self->compress.err = jpeg_std_error(&self->error);
 	jpeg_create_compress(&self->compress);
+	self->buffer = NULL;
+	self->size = 0;
 	return self;
 }
 
 void jpeg_compressor_destroy(jpeg_compressor_t* self) {
 	jpeg_destroy_compress(&self->compress);
+	free(self->buffer);
 	free(self);
 }
 
-size_t jpeg_compressor_compress(jpeg_compressor_t* self, uint8_t* dst, size_t dst_size, uint32_t const* src, size_t stride, size_t w, size_t h) {
-	unsigned long dst_size_ul = dst_size;
-	jpeg_mem_dest(&self->compress, &dst, &dst_size_ul);
+// subsampling: 0 = 4:4:4, 1 = 4:2:2, 2 = 4:2:0, 3 = grayscale.
+// *dst is valid until the next call.
+size_t jpeg_compressor_compress(jpeg_compressor_t* self, uint8_t const** dst, uint32_t const* src, size_t stride, size_t w, size_t h, int quality, int subsampling) {
+	unsigned char* buffer = self->buffer;
+	unsigned long size = self->size;
+	jpeg_mem_dest(&self->compress, &buffer, &size);
 	self->compress.image_width = w;
 	self->compress.image_height = h;
 	self->compress.input_components = 4;
 	self->compress.in_color_space = JCS_EXT_BGRX;
 	jpeg_set_defaults(&self->compress);
-	// quality: 7-bit DC value.
-	jpeg_set_quality(&self->compress, 93, TRUE);
-	// 4:4:4 sampling.
-	self->compress.comp_info[0].h_samp_factor = 1;
-	self->compress.comp_info[1].h_samp_factor = 1;
-	self->compress.comp_info[2].h_samp_factor = 1;
-	self->compress.comp_info[0].v_samp_factor = 1;
-	self->compress.comp_info[1].v_samp_factor = 1;
-	self->compress.comp_info[2].v_samp_factor = 1;
+	// default quality 93: 7-bit DC value.
+	jpeg_set_quality(&self->compress, quality, TRUE);
+	if (subsampling == 3) {
+		jpeg_set_colorspace(&self->compress, JCS_GRAYSCALE);
+	}
+	else {
+		self->compress.comp_info[0].h_samp_factor = subsampling >= 1 ? 2 : 1;
+		self->compress.comp_info[1].h_samp_factor = 1;
+		self->compress.comp_info[2].h_samp_factor = 1;
+		self->compress.comp_info[0].v_samp_factor = subsampling >= 2 ? 2 : 1;
+		self->compress.comp_info[1].v_samp_factor = 1;
+		self->compress.comp_info[2].v_samp_factor = 1;
+	}
 #if 0
 	// omit quantization tables.
 	for (size_t i = 0; i < NUM_QUANT_TBLS; ++i) {
@@ -74,5 +87,11 @@ size_t jpeg_compressor_compress(jpeg_compressor_t* self, uint8_t* dst, size_t ds
 	}
 	jpeg_finish_compress(&self->compress);
 
-	return dst_size_ul;
+	if (buffer != self->buffer) {
+		free(self->buffer);
+		self->buffer = buffer;
+		self->size = size;
+	}
+	*dst = buffer;
+	return size;
 }
@@ -0,0 +1,19 @@
+pub mod capture;
+pub mod comparator;
+pub mod encoder;
+pub mod framebuffer;
+#[cfg(feature = "h264")]
+pub mod h264;
+#[cfg(feature = "rust-jpeg")]
+pub mod jpeg;
+pub mod motion;
+pub mod rawvideo;
+pub mod server;
+#[cfg(feature = "x11")]
+pub mod x11;
+
+pub use capture::Source;
+pub use comparator::Comparator;
+pub use encoder::Encoder;
+pub use framebuffer::Framebuffer;
+pub use server::{Builder, Clipboard, Handler, VncServer};
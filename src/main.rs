@@ -1,18 +1,203 @@
-mod comparator;
-mod encoder;
-mod server;
+use mfxvnc::{capture, comparator, encoder, rawvideo, server};
 use std::*;
 
+const USAGE: &str = "usage: mfxvnc [--listen ADDR] [--monitor INDEX|NAME | --stitch] [--x11 DISPLAY [--window ID]] \
+                     [--raw PATH WxH [--format bgr0|rgb0|bgr24|rgb24] | --y4m PATH] [--fps N] [--repeat] \
+                     [--region WxH+X+Y]";
+
+#[derive(Clone, Default)]
+struct Input {
+    path: String,
+    y4m: bool,
+    size: (usize, usize),
+    format: Option<rawvideo::RawFormat>,
+    fps: f64,
+    repeat: bool,
+}
+
+impl Input {
+    fn open(&self) -> io::Result<Box<dyn capture::Source>> {
+        if self.path == "-" {
+            return self.source(io::stdin());
+        }
+        let file = fs::File::open(&self.path)?;
+        // named pipes cannot be rewound.
+        if self.repeat && file.metadata()?.is_file() {
+            let source = self.source_of(file)?;
+            Ok(Box::new(source.repeat()?))
+        } else {
+            self.source(file)
+        }
+    }
+
+    fn source<R: io::Read + 'static>(&self, reader: R) -> io::Result<Box<dyn capture::Source>> {
+        Ok(Box::new(self.source_of(reader)?))
+    }
+
+    fn source_of<R: io::Read>(&self, reader: R) -> io::Result<rawvideo::RawSource<R>> {
+        let source = if self.y4m {
+            rawvideo::RawSource::y4m(reader)?
+        } else {
+            let format = self.format.unwrap_or(rawvideo::RawFormat::Bgr0);
+            rawvideo::RawSource::raw(reader, self.size.0, self.size.1, format)
+        };
+        Ok(if self.fps > 0.0 { source.fps(self.fps) } else { source })
+    }
+}
+
+fn parse_size(s: &str) -> Option<(usize, usize)> {
+    let mut it = s.split('x').map(|v| v.parse().ok());
+    match (it.next(), it.next(), it.next()) {
+        (Some(Some(w)), Some(Some(h)), None) if w > 0 && h > 0 => Some((w, h)),
+        _ => None,
+    }
+}
+
+// X geometry, e.g. "640x480+100+50".
+fn parse_region(s: &str) -> Option<(usize, usize, usize, usize)> {
+    let mut it = s.splitn(2, '+');
+    let (w, h) = parse_size(it.next()?)?;
+    let mut it = it.next()?.split('+').map(|v| v.parse().ok());
+    match (it.next(), it.next(), it.next()) {
+        (Some(Some(x)), Some(Some(y)), None) => Some((x, y, w, h)),
+        _ => None,
+    }
+}
+
+fn parse_window(s: &str) -> Option<u32> {
+    if s.starts_with("0x") {
+        u32::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
 fn main() -> Result<(), Box<dyn error::Error>> {
-    //server::VncServer::<comparator::StripComparator, encoder::RandomColorEncoder>::listen( "0.0.0.0:5900" )?;
-    //server::VncServer::<comparator::StripComparator, encoder::TightRawEncoder>::listen( "0.0.0.0:5900" )?;
-    //server::VncServer::<comparator::StripComparator, encoder::TightGradientEncoder>::listen( "0.0.0.0:5900" )?;
-    //server::VncServer::<comparator::StripComparator, encoder::TightAdaptiveEncoder>::listen( "0.0.0.0:5900" )?;
-    //server::VncServer::<comparator::StripComparator, encoder::TightJpegEncoder>::listen( "0.0.0.0:5900" )?;
-    //server::VncServer::<comparator::QuadtreeComparator, encoder::RandomColorEncoder>::listen( "0.0.0.0:5900" )?;
-    //server::VncServer::<comparator::QuadtreeComparator, encoder::TightRawEncoder>::listen( "0.0.0.0:5900" )?;
-    //server::VncServer::<comparator::QuadtreeComparator, encoder::TightGradientEncoder>::listen( "0.0.0.0:5900" )?;
-    //server::VncServer::<comparator::QuadtreeComparator, encoder::TightAdaptiveEncoder>::listen( "0.0.0.0:5900" )?;
-    server::VncServer::<comparator::QuadtreeComparator, encoder::TightJpegEncoder>::listen("0.0.0.0:5900")?;
+    let mut addr = "0.0.0.0:5900".to_string();
+    let mut input = None;
+    let mut x11 = None;
+    let mut window = None;
+    let mut region = None;
+    let mut monitor = None;
+    let mut stitch = false;
+    let mut fps = 0.0;
+    let mut repeat = false;
+    let mut format = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(USAGE);
+        match arg.as_str() {
+            "--listen" => addr = value()?,
+            "--x11" => x11 = Some(value()?),
+            "--window" => window = Some(parse_window(&value()?).ok_or(USAGE)?),
+            "--monitor" => monitor = Some(value()?),
+            "--stitch" => stitch = true,
+            "--region" => region = Some(parse_region(&value()?).ok_or(USAGE)?),
+            "--raw" => {
+                let path = value()?;
+                let size = parse_size(&value()?).ok_or(USAGE)?;
+                input = Some(Input {
+                    path,
+                    size,
+                    ..Input::default()
+                });
+            }
+            "--y4m" => {
+                input = Some(Input {
+                    path: value()?,
+                    y4m: true,
+                    ..Input::default()
+                });
+            }
+            "--format" => format = Some(value()?.parse()?),
+            "--fps" => fps = value()?.parse()?,
+            "--repeat" => repeat = true,
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    //server::Builder::<comparator::StripComparator, encoder::RandomColorEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::StripComparator, encoder::TightRawEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::StripComparator, encoder::TightGradientEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::StripComparator, encoder::TightAdaptiveEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::StripComparator, encoder::TightJpegEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, encoder::RandomColorEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, encoder::TightRawEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, encoder::TightGradientEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, encoder::TightAdaptiveEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, encoder::TightJpegEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, encoder::TightPngEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, encoder::ZrleEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, encoder::ZlibEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, encoder::TrleEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, encoder::HextileEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, encoder::TightVideoRegionEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, encoder::TightTrialEncoder>::new().build().listen( "0.0.0.0:5900" )?;
+    //server::Builder::<comparator::QuadtreeComparator, mfxvnc::h264::H264Encoder>::new().build().listen( "0.0.0.0:5900" )?; // --features h264
+    let mut builder = server::Builder::<comparator::QuadtreeComparator, encoder::TightMixedEncoder>::new();
+    if let Some(display) = x11 {
+        #[cfg(feature = "x11")]
+        {
+            // the root window is already a stitched desktop; a monitor is a part of it.
+            let crop = match monitor.take() {
+                Some(monitor) => {
+                    let monitors = mfxvnc::x11::X11Source::monitors(Some(&display))?;
+                    let index = monitor.parse::<usize>().ok();
+                    let (_, s) = monitors
+                        .into_iter()
+                        .enumerate()
+                        .find(|(i, (name, _))| *name == monitor || Some(*i) == index)
+                        .map(|(_, m)| m)
+                        .ok_or("no such monitor.")?;
+                    Some((s.x, s.y, s.w, s.h))
+                }
+                None => None,
+            };
+            builder = builder.source(move || match window {
+                Some(window) => Ok(Box::new(mfxvnc::x11::X11Source::window(Some(&display), window)?)),
+                None => {
+                    let source = mfxvnc::x11::X11Source::new(Some(&display))?;
+                    match crop {
+                        Some((x, y, w, h)) => Ok(Box::new(capture::Crop::new(source, x, y, w, h))),
+                        None => Ok(Box::new(source)),
+                    }
+                }
+            });
+        }
+        #[cfg(not(feature = "x11"))]
+        {
+            let _ = (display, window);
+            return Err("built without the x11 feature.".into());
+        }
+    } else if stitch || monitor.is_some() {
+        #[cfg(feature = "scrap")]
+        {
+            if stitch {
+                builder = builder.source(|| Ok(Box::new(capture::ScrapSource::all()?)));
+            } else if let Some(monitor) = monitor.take() {
+                let index = monitor
+                    .parse::<usize>()
+                    .map_err(|_| "scrap monitors are specified by index.")?;
+                builder = builder.source(move || Ok(Box::new(capture::ScrapSource::display(index)?)));
+            }
+        }
+        #[cfg(not(feature = "scrap"))]
+        {
+            return Err("built without the scrap feature.".into());
+        }
+    }
+    if let Some(input) = input {
+        let input = Input {
+            format,
+            fps,
+            repeat,
+            ..input
+        };
+        builder = builder.source(move || input.open());
+    }
+    if let Some((x, y, w, h)) = region {
+        builder = builder.map_source(move |source| Box::new(capture::Crop::new(source, x, y, w, h)));
+    }
+    builder.build().listen(addr)?;
     Ok(())
 }
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::*;
+
+/// A rectangle (x0, y0)-(x1, y1) of the next frame which is the previous one moved by (dx, dy).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Motion {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+    pub dx: isize,
+    pub dy: isize,
+}
+
+impl Motion {
+    pub fn src_x(&self) -> usize {
+        (self.x0 as isize - self.dx) as usize
+    }
+
+    pub fn src_y(&self) -> usize {
+        (self.y0 as isize - self.dy) as usize
+    }
+
+    /// Moves the pixels in the same way as a CopyRect on the client.
+    pub fn apply(&self, screen: &mut [u32], stride: usize) {
+        let w = self.x1 - self.x0;
+        let mut copy = |y: usize| {
+            let src = stride * (y as isize - self.dy) as usize + self.src_x();
+            screen.copy_within(src..src + w, stride * y + self.x0);
+        };
+        // the source rows must be read before they are overwritten.
+        if self.dy > 0 {
+            (self.y0..self.y1).rev().for_each(&mut copy);
+        } else {
+            (self.y0..self.y1).for_each(&mut copy);
+        }
+    }
+}
+
+/// Finds a scroll by hashing the rows, or a moved window by matching blocks, between the previous frame and the
+/// next one.  Pixels of the previous frame are 0x00RRGGBB, as the comparators leave them.
+pub struct MotionDetector {
+    rows: HashMap<u64, usize>,
+    blocks: HashMap<u64, (usize, usize)>,
+    votes: HashMap<(isize, isize), usize>,
+}
+
+impl MotionDetector {
+    const MIN_AREA: usize = 4096;
+    const MIN_ROWS: usize = 8;
+    const MIN_VOTES: usize = 8;
+    const BLOCK_W: usize = 32;
+    // a power of 2.
+    const BLOCK_STEP_Y: usize = 4;
+    const MAX_BLOCKS: usize = 4096;
+    // the bounding box of the changes is found from every this many rows, which is less than `MIN_ROWS`.
+    const BBOX_STEP_Y: usize = 4;
+    // larger areas are sampled.
+    const MAX_SEARCH_AREA: usize = 1 << 19;
+    const BASE: u64 = 0x100_0000_01b3;
+
+    pub fn new() -> Self {
+        MotionDetector {
+            rows: HashMap::new(),
+            blocks: HashMap::new(),
+            votes: HashMap::new(),
+        }
+    }
+
+    /// Searches the changes in the region (x0, y0)-(x1, y1) of the frame of size w x h.  The changes in `exclude`,
+    /// e.g. the video region which is sent separately, are ignored.
+    pub fn detect(
+        &mut self,
+        prev: &[u32],
+        next: &[u32],
+        stride: usize,
+        w: usize,
+        h: usize,
+        (x0, y0, x1, y1): (usize, usize, usize, usize),
+        exclude: Option<(usize, usize, usize, usize)>,
+    ) -> Option<Motion> {
+        let frame = Frame {
+            prev,
+            next,
+            stride,
+            w,
+            h,
+        };
+
+        // the bounding box of the changes, widened by the rows skipped.
+        let (ex0, ey0, ex1, ey1) = exclude.unwrap_or((0, 0, 0, 0));
+        let changed = |x: usize, y: usize| {
+            prev[stride * y + x] != next[stride * y + x] & 0x00ffffff && !(ex0 <= x && x < ex1 && ey0 <= y && y < ey1)
+        };
+        let mut bx0 = x1;
+        let mut by0 = y1;
+        let mut bx1 = x0;
+        let mut by1 = y0;
+        for y in (y0..y1).step_by(Self::BBOX_STEP_Y) {
+            let mut xs = (x0..x1).filter(|&x| changed(x, y));
+            if let Some(first) = xs.next() {
+                let last = xs.last().unwrap_or(first);
+                bx0 = cmp::min(bx0, first);
+                bx1 = cmp::max(bx1, last + 1);
+                by0 = cmp::min(by0, cmp::max(y0, y.saturating_sub(Self::BBOX_STEP_Y - 1)));
+                by1 = cmp::min(y + Self::BBOX_STEP_Y, y1);
+            }
+        }
+        if bx0 >= bx1 || (bx1 - bx0) * (by1 - by0) < Self::MIN_AREA {
+            return None;
+        }
+        let bbox = (bx0, by0, bx1, by1);
+
+        self.detect_scroll(&frame, bbox)
+            .or_else(|| self.detect_move(&frame, bbox))
+    }
+
+    // the step of the samples, which is odd so that it meets the blocks every `BLOCK_STEP_Y` rows at any offset.
+    fn sample_step(x0: usize, y0: usize, x1: usize, y1: usize) -> usize {
+        (x1 - x0) * (y1 - y0) / Self::MAX_SEARCH_AREA | 1
+    }
+
+    fn detect_scroll(&mut self, f: &Frame, (x0, y0, x1, y1): (usize, usize, usize, usize)) -> Option<Motion> {
+        // the rows of large areas are hashed from every k-th pixel.
+        let k = Self::sample_step(x0, y0, x1, y1);
+        let hash = |screen: &[u32], y: usize| {
+            let row = &screen[f.stride * y + x0..f.stride * y + x1];
+            // flat rows match anywhere.
+            if row.iter().step_by(k).all(|&p| p & 0x00ffffff == row[0] & 0x00ffffff) {
+                None
+            } else {
+                Some(row.iter().step_by(k).fold(0, |h: u64, &p| {
+                    h.wrapping_mul(Self::BASE).wrapping_add((p & 0x00ffffff) as u64)
+                }))
+            }
+        };
+
+        self.rows.clear();
+        for y in y0..y1 {
+            if let Some(h) = hash(f.prev, y) {
+                self.rows.entry(h).or_insert(y);
+            }
+        }
+        self.votes.clear();
+        for y in y0..y1 {
+            if let Some(&py) = hash(f.next, y).and_then(|h| self.rows.get(&h)) {
+                if py != y {
+                    *self.votes.entry((0, y as isize - py as isize)).or_insert(0) += 1;
+                }
+            }
+        }
+        let (_, dy) = self.best_vote()?;
+
+        // the longest run of the rows moved by dy.
+        let mut best = (0, 0);
+        let mut start = None;
+        for y in y0..=y1 {
+            let moved = y < y1 && f.matches_row(x0, x1, y, 0, dy);
+            match (moved, start) {
+                (true, None) => start = Some(y),
+                (false, Some(s)) => {
+                    if y - s > best.1 - best.0 {
+                        best = (s, y);
+                    }
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+        let motion = Motion {
+            x0,
+            y0: best.0,
+            x1,
+            y1: best.1,
+            dx: 0,
+            dy,
+        };
+        if best.1 - best.0 >= Self::MIN_ROWS && (x1 - x0) * (best.1 - best.0) >= Self::MIN_AREA {
+            Some(motion)
+        } else {
+            None
+        }
+    }
+
+    fn detect_move(&mut self, f: &Frame, (x0, y0, x1, y1): (usize, usize, usize, usize)) -> Option<Motion> {
+        let bw = Self::BLOCK_W;
+        if x1 - x0 < bw {
+            return None;
+        }
+
+        // sample the changed, non-flat blocks of the next frame.
+        self.blocks.clear();
+        'sample: for y in (y0..y1).step_by(Self::BLOCK_STEP_Y) {
+            for x in (x0..x1 - bw + 1).step_by(bw) {
+                let block = &f.next[f.stride * y + x..f.stride * y + x + bw];
+                let flat = block.iter().all(|&p| p & 0x00ffffff == block[0] & 0x00ffffff);
+                if flat || f.matches_row(x, x + bw, y, 0, 0) {
+                    continue;
+                }
+                self.blocks.entry(Self::hash(block)).or_insert((x, y));
+                if self.blocks.len() >= Self::MAX_BLOCKS {
+                    break 'sample;
+                }
+            }
+        }
+        if self.blocks.is_empty() {
+            return None;
+        }
+
+        // find them in the previous frame by a rolling hash, in every k-th row of large areas.
+        let top = (0..bw - 1).fold(1u64, |v, _| v.wrapping_mul(Self::BASE));
+        self.votes.clear();
+        for y in (y0..y1).step_by(Self::sample_step(x0, y0, x1, y1)) {
+            let row = &f.prev[f.stride * y..];
+            let mut h = Self::hash(&row[x0..x0 + bw - 1]);
+            for x in x0..x1 - bw + 1 {
+                h = h
+                    .wrapping_mul(Self::BASE)
+                    .wrapping_add((row[x + bw - 1] & 0x00ffffff) as u64);
+                if let Some(&(nx, ny)) = self.blocks.get(&h) {
+                    let v = (nx as isize - x as isize, ny as isize - y as isize);
+                    if v != (0, 0) {
+                        *self.votes.entry(v).or_insert(0) += 1;
+                    }
+                }
+                h = h.wrapping_sub(((row[x] & 0x00ffffff) as u64).wrapping_mul(top));
+            }
+        }
+        let (dx, dy) = self.best_vote()?;
+
+        // grow the rectangle from the sampled blocks which have moved by (dx, dy), and keep the largest.
+        let mut best: Option<Motion> = None;
+        for &(x, y) in self.blocks.values() {
+            if !f.matches_row(x, x + bw, y, dx, dy) {
+                continue;
+            }
+            if let Some(b) = best {
+                if b.x0 <= x && x < b.x1 && b.y0 <= y && y < b.y1 {
+                    continue;
+                }
+            }
+            let m = f.grow(x, y, x + bw, y + 1, dx, dy);
+            if best.map_or(true, |b| Self::area(&m) > Self::area(&b)) {
+                best = Some(m);
+            }
+        }
+        best.filter(|m| Self::area(m) >= Self::MIN_AREA)
+    }
+
+    fn best_vote(&self) -> Option<(isize, isize)> {
+        self.votes
+            .iter()
+            .max_by_key(|&(_, &n)| n)
+            .filter(|&(_, &n)| n >= Self::MIN_VOTES)
+            .map(|(&v, _)| v)
+    }
+
+    fn hash(pixels: &[u32]) -> u64 {
+        pixels.iter().fold(0, |h: u64, &p| {
+            h.wrapping_mul(Self::BASE).wrapping_add((p & 0x00ffffff) as u64)
+        })
+    }
+
+    fn area(m: &Motion) -> usize {
+        (m.x1 - m.x0) * (m.y1 - m.y0)
+    }
+}
+
+struct Frame<'a> {
+    prev: &'a [u32],
+    next: &'a [u32],
+    stride: usize,
+    w: usize,
+    h: usize,
+}
+
+impl<'a> Frame<'a> {
+    // whether the row [x0, x1) of the next frame is the previous one moved by (dx, dy).
+    fn matches_row(&self, x0: usize, x1: usize, y: usize, dx: isize, dy: isize) -> bool {
+        let sx = x0 as isize - dx;
+        let sy = y as isize - dy;
+        if sx < 0 || sy < 0 || sx as usize + (x1 - x0) > self.w || sy as usize >= self.h {
+            return false;
+        }
+        let src = &self.prev[self.stride * sy as usize + sx as usize..];
+        let dst = &self.next[self.stride * y + x0..self.stride * y + x1];
+        dst.iter().zip(src.iter()).all(|(&q, &p)| q & 0x00ffffff == p)
+    }
+
+    fn matches_col(&self, x: usize, y0: usize, y1: usize, dx: isize, dy: isize) -> bool {
+        (y0..y1).all(|y| self.matches_row(x, x + 1, y, dx, dy))
+    }
+
+    // extends the rows, the columns, and then the rows again over the whole width.
+    fn grow(&self, x0: usize, y0: usize, x1: usize, y1: usize, dx: isize, dy: isize) -> Motion {
+        let (mut x0, mut y0, mut x1, mut y1) = (x0, y0, x1, y1);
+        while y0 > 0 && self.matches_row(x0, x1, y0 - 1, dx, dy) {
+            y0 -= 1;
+        }
+        while y1 < self.h && self.matches_row(x0, x1, y1, dx, dy) {
+            y1 += 1;
+        }
+        while x0 > 0 && self.matches_col(x0 - 1, y0, y1, dx, dy) {
+            x0 -= 1;
+        }
+        while x1 < self.w && self.matches_col(x1, y0, y1, dx, dy) {
+            x1 += 1;
+        }
+        while y0 > 0 && self.matches_row(x0, x1, y0 - 1, dx, dy) {
+            y0 -= 1;
+        }
+        while y1 < self.h && self.matches_row(x0, x1, y1, dx, dy) {
+            y1 += 1;
+        }
+        Motion { x0, y0, x1, y1, dx, dy }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const W: usize = 320;
+    const H: usize = 240;
+
+    fn noise(x: usize, y: usize, seed: usize) -> u32 {
+        let v = (x.wrapping_mul(2654435761) ^ y.wrapping_mul(40503) ^ seed).wrapping_mul(2246822519);
+        (v >> 7) as u32 & 0x00ffffff
+    }
+
+    // a text area at (20, 10)-(300, 200) scrolled by `dy` rows, with blank rows revealed.
+    fn scrolled(dy: isize) -> (Vec<u32>, Vec<u32>) {
+        let inside = |x: usize, y: usize| (20..300).contains(&x) && (10..200).contains(&y);
+        let prev: Vec<u32> = (0..W * H)
+            .map(|i| {
+                if inside(i % W, i / W) {
+                    noise(i % W, i / W, 1)
+                } else {
+                    0x336699
+                }
+            })
+            .collect();
+        let mut next = prev.clone();
+        for y in 10..200 {
+            for x in 20..300 {
+                let sy = y as isize - dy;
+                next[W * y + x] = if (10..200).contains(&sy) {
+                    prev[W * sy as usize + x]
+                } else {
+                    0x101010
+                };
+            }
+        }
+        (prev, next)
+    }
+
+    #[test]
+    fn scroll_up() {
+        let (prev, next) = scrolled(-7);
+        let m = MotionDetector::new().detect(&prev, &next, W, W, H, (0, 0, W, H), None);
+        let expected = Motion {
+            x0: 20,
+            y0: 10,
+            x1: 300,
+            y1: 193,
+            dx: 0,
+            dy: -7,
+        };
+        assert_eq!(m, Some(expected));
+        assert_eq!((expected.src_x(), expected.src_y()), (20, 17));
+    }
+
+    #[test]
+    fn scroll_down() {
+        let (prev, next) = scrolled(7);
+        let mut detector = MotionDetector::new();
+        let m = detector.detect(&prev, &next, W, W, H, (0, 0, W, H), None);
+        let expected = Motion {
+            x0: 20,
+            y0: 17,
+            x1: 300,
+            y1: 200,
+            dx: 0,
+            dy: 7,
+        };
+        assert_eq!(m, Some(expected));
+
+        // nothing outside the excluded area changes.
+        assert_eq!(
+            detector.detect(&prev, &next, W, W, H, (0, 0, W, H), Some((20, 10, 300, 200))),
+            None
+        );
+    }
+
+    #[test]
+    fn moved_block() {
+        // a 96 x 64 window from (40, 30) to (120, 100) over a background of noise, with alpha bits in the next frame.
+        let window = |x: usize, y: usize, x0: usize, y0: usize| {
+            if (x0..x0 + 96).contains(&x) && (y0..y0 + 64).contains(&y) {
+                Some(noise(x - x0, y - y0, 2))
+            } else {
+                None
+            }
+        };
+        let prev: Vec<u32> = (0..W * H)
+            .map(|i| window(i % W, i / W, 40, 30).unwrap_or_else(|| noise(i % W, i / W, 3)))
+            .collect();
+        let next: Vec<u32> = (0..W * H)
+            .map(|i| 0xff000000 | window(i % W, i / W, 120, 100).unwrap_or_else(|| noise(i % W, i / W, 3)))
+            .collect();
+        let m = MotionDetector::new().detect(&prev, &next, W, W, H, (0, 0, W, H), None);
+        let expected = Motion {
+            x0: 120,
+            y0: 100,
+            x1: 216,
+            y1: 164,
+            dx: 80,
+            dy: 70,
+        };
+        assert_eq!(m, Some(expected));
+        assert_eq!((expected.src_x(), expected.src_y()), (40, 30));
+
+        let mut applied = prev.clone();
+        expected.apply(&mut applied, W);
+        for y in 100..164 {
+            for x in 120..216 {
+                assert_eq!(applied[W * y + x], next[W * y + x] & 0x00ffffff);
+            }
+        }
+    }
+
+    #[test]
+    fn apply() {
+        let (w, h) = (4, 6);
+        let screen: Vec<u32> = (0..(w * h) as u32).collect();
+        let row = |screen: &[u32], y: usize| screen[w * y..w * y + w].to_vec();
+
+        // up: rows 0-3 from rows 2-5.
+        let mut up = screen.clone();
+        Motion {
+            x0: 0,
+            y0: 0,
+            x1: w,
+            y1: 4,
+            dx: 0,
+            dy: -2,
+        }
+        .apply(&mut up, w);
+        for y in 0..h {
+            assert_eq!(row(&up, y), row(&screen, if y < 4 { y + 2 } else { y }));
+        }
+
+        // down: rows 2-5 from the overlapping rows 0-3.
+        let mut down = screen.clone();
+        Motion {
+            x0: 0,
+            y0: 2,
+            x1: w,
+            y1: 6,
+            dx: 0,
+            dy: 2,
+        }
+        .apply(&mut down, w);
+        for y in 0..h {
+            assert_eq!(row(&down, y), row(&screen, if y < 2 { y } else { y - 2 }));
+        }
+
+        // a part of the rows to the left.
+        let mut left = screen.clone();
+        Motion {
+            x0: 0,
+            y0: 1,
+            x1: 2,
+            y1: 3,
+            dx: -1,
+            dy: 0,
+        }
+        .apply(&mut left, w);
+        assert_eq!(row(&left, 1), &[5, 6, 6, 7]);
+        assert_eq!(row(&left, 2), &[9, 10, 10, 11]);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::capture;
+use std::io::{BufRead, Read, Seek};
+use std::*;
+
+/// Pixel layouts of `ffmpeg -f rawvideo`, named after ffmpeg's pix_fmt.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RawFormat {
+    Bgr0,
+    Rgb0,
+    Bgr24,
+    Rgb24,
+}
+
+impl str::FromStr for RawFormat {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "bgr0" | "bgra" => Ok(RawFormat::Bgr0),
+            "rgb0" | "rgba" => Ok(RawFormat::Rgb0),
+            "bgr24" => Ok(RawFormat::Bgr24),
+            "rgb24" => Ok(RawFormat::Rgb24),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "raw format")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Layout {
+    Raw(RawFormat),
+    // chroma subsampling shifts of a planar 8 bit YCbCr frame.
+    Yuv(usize, usize),
+    Mono,
+}
+
+/// A capture source reading uncompressed frames from a file, a named pipe or stdin.
+pub struct RawSource<R: io::Read> {
+    reader: io::BufReader<R>,
+    w: usize,
+    h: usize,
+    layout: Layout,
+    y4m: bool,
+    interval: Option<time::Duration>,
+    deadline: time::Instant,
+    rewind: Option<Box<dyn FnMut(&mut io::BufReader<R>) -> io::Result<()>>>,
+    buf: Vec<u8>,
+    screen: Vec<u32>,
+}
+
+impl<R: io::Read> RawSource<R> {
+    pub fn raw(reader: R, w: usize, h: usize, format: RawFormat) -> Self {
+        Self::with_layout(io::BufReader::new(reader), w, h, Layout::Raw(format), false)
+    }
+
+    /// Reads a YUV4MPEG2 stream; the geometry is taken from its header.
+    pub fn y4m(reader: R) -> io::Result<Self> {
+        let mut reader = io::BufReader::new(reader);
+        let header = Self::read_line(&mut reader)?;
+        let mut tokens = header.split(' ');
+        if tokens.next() != Some("YUV4MPEG2") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "y4m signature"));
+        }
+
+        let mut w = 0;
+        let mut h = 0;
+        let mut layout = Layout::Yuv(1, 1);
+        let mut interval = None;
+        for token in tokens.filter(|t| !t.is_empty()) {
+            let (key, value) = token.split_at(1);
+            match key {
+                "W" => {
+                    w = value
+                        .parse()
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "y4m width"))?
+                }
+                "H" => {
+                    h = value
+                        .parse()
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "y4m height"))?
+                }
+                "F" => {
+                    let mut it = value.split(':').map(|v| v.parse::<u64>().ok());
+                    if let (Some(Some(n)), Some(Some(d))) = (it.next(), it.next()) {
+                        if n > 0 {
+                            interval = Some(time::Duration::from_nanos(1_000_000_000 * d / n));
+                        }
+                    }
+                }
+                "C" => {
+                    layout = match value {
+                        "420" | "420jpeg" | "420paldv" | "420mpeg2" => Layout::Yuv(1, 1),
+                        "422" => Layout::Yuv(1, 0),
+                        "444" => Layout::Yuv(0, 0),
+                        "mono" => Layout::Mono,
+                        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "y4m colour space")),
+                    }
+                }
+                _ => (),
+            }
+        }
+        if w == 0 || h == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "y4m geometry"));
+        }
+
+        let mut this = Self::with_layout(reader, w, h, layout, true);
+        this.interval = interval;
+        Ok(this)
+    }
+
+    fn with_layout(reader: io::BufReader<R>, w: usize, h: usize, layout: Layout, y4m: bool) -> Self {
+        RawSource {
+            reader,
+            w,
+            h,
+            layout,
+            y4m,
+            interval: None,
+            deadline: time::Instant::now(),
+            rewind: None,
+            buf: Vec::new(),
+            screen: vec![0; w * h],
+        }
+    }
+
+    /// Paces the frames at the given rate instead of reading as fast as possible (or the y4m frame rate).
+    pub fn fps(mut self, fps: f64) -> Self {
+        self.interval = if fps > 0.0 {
+            Some(time::Duration::from_secs_f64(1.0 / fps))
+        } else {
+            None
+        };
+        self
+    }
+
+    fn frame_size(&self) -> usize {
+        let n = self.w * self.h;
+        match self.layout {
+            Layout::Raw(RawFormat::Bgr0) | Layout::Raw(RawFormat::Rgb0) => 4 * n,
+            Layout::Raw(RawFormat::Bgr24) | Layout::Raw(RawFormat::Rgb24) => 3 * n,
+            Layout::Yuv(sx, sy) => n + 2 * (((self.w + sx) >> sx) * ((self.h + sy) >> sy)),
+            Layout::Mono => n,
+        }
+    }
+
+    fn read_line(reader: &mut io::BufReader<R>) -> io::Result<String> {
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line)?;
+        if line.pop() != Some(b'\n') {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        String::from_utf8(line).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "y4m header"))
+    }
+
+    fn read_frame(&mut self) -> io::Result<()> {
+        if self.y4m {
+            let line = Self::read_line(&mut self.reader)?;
+            if !line.starts_with("FRAME") {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "y4m frame header"));
+            }
+        }
+        let size = self.frame_size();
+        self.buf.resize(size, 0);
+        self.reader.read_exact(&mut self.buf)
+    }
+
+    fn convert(&mut self) {
+        let w = self.w;
+        let h = self.h;
+        let src = &self.buf;
+        match self.layout {
+            Layout::Raw(RawFormat::Bgr0) => {
+                for (dst, src) in self.screen.iter_mut().zip(src.chunks_exact(4)) {
+                    *dst = u32::from_le_bytes([src[0], src[1], src[2], 0]);
+                }
+            }
+            Layout::Raw(RawFormat::Rgb0) => {
+                for (dst, src) in self.screen.iter_mut().zip(src.chunks_exact(4)) {
+                    *dst = u32::from_le_bytes([src[2], src[1], src[0], 0]);
+                }
+            }
+            Layout::Raw(RawFormat::Bgr24) => {
+                for (dst, src) in self.screen.iter_mut().zip(src.chunks_exact(3)) {
+                    *dst = u32::from_le_bytes([src[0], src[1], src[2], 0]);
+                }
+            }
+            Layout::Raw(RawFormat::Rgb24) => {
+                for (dst, src) in self.screen.iter_mut().zip(src.chunks_exact(3)) {
+                    *dst = u32::from_le_bytes([src[2], src[1], src[0], 0]);
+                }
+            }
+            Layout::Yuv(sx, sy) => {
+                let cw = (w + sx) >> sx;
+                let ch = (h + sy) >> sy;
+                let (ys, cs) = src.split_at(w * h);
+                let (us, vs) = cs.split_at(cw * ch);
+                for y in 0..h {
+                    for x in 0..w {
+                        let i = cw * (y >> sy) + (x >> sx);
+                        self.screen[w * y + x] = Self::ycbcr(ys[w * y + x], us[i], vs[i]);
+                    }
+                }
+            }
+            Layout::Mono => {
+                for (dst, &src) in self.screen.iter_mut().zip(src.iter()) {
+                    *dst = Self::ycbcr(src, 128, 128);
+                }
+            }
+        }
+    }
+
+    // ITU-R BT.601, limited range.
+    fn ycbcr(y: u8, u: u8, v: u8) -> u32 {
+        let c = 298 * (y as i32 - 16) + 128;
+        let d = u as i32 - 128;
+        let e = v as i32 - 128;
+        let r = cmp::max(0, cmp::min(255, (c + 409 * e) >> 8)) as u32;
+        let g = cmp::max(0, cmp::min(255, (c - 100 * d - 208 * e) >> 8)) as u32;
+        let b = cmp::max(0, cmp::min(255, (c + 516 * d) >> 8)) as u32;
+        (r << 16) | (g << 8) | b
+    }
+}
+
+impl<R: io::Read + io::Seek> RawSource<R> {
+    /// Restarts from the first frame at the end of the stream, e.g. for benchmarking with a recorded file.
+    pub fn repeat(mut self) -> io::Result<Self> {
+        let start = self.reader.stream_position()?;
+        self.rewind = Some(Box::new(move |reader| {
+            reader.seek(io::SeekFrom::Start(start)).map(|_| ())
+        }));
+        Ok(self)
+    }
+}
+
+impl<R: io::Read> capture::Source for RawSource<R> {
+    fn width(&self) -> usize {
+        self.w
+    }
+
+    fn height(&self) -> usize {
+        self.h
+    }
+
+    fn frame(&mut self) -> io::Result<capture::Frame<'_>> {
+        if let Some(interval) = self.interval {
+            let now = time::Instant::now();
+            if now < self.deadline {
+                thread::sleep(self.deadline - now);
+            }
+            self.deadline = cmp::max(self.deadline, now) + interval;
+        }
+
+        match self.read_frame() {
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof && self.rewind.is_some() => {
+                (self.rewind.as_mut().unwrap())(&mut self.reader)?;
+                self.read_frame()?;
+            }
+            result => result?,
+        }
+        self.convert();
+
+        Ok(capture::Frame {
+            data: &self.screen,
+            width: self.w,
+            height: self.h,
+            stride: self.w,
+            format: capture::PixelFormat::Bgrx,
+            timestamp: time::Instant::now(),
+            damage: capture::Damage::Unknown,
+        })
+    }
+}
@@ -1,37 +1,682 @@
+use crate::capture;
 use crate::comparator;
 use crate::encoder;
+use crate::framebuffer;
+use crate::motion;
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
-use scrap;
 use std::io::{Read, Write};
 use std::*;
 
+pub trait Handler: Send + Sync {
+    fn authenticate(&self, _peer: net::SocketAddr) -> bool {
+        true
+    }
+
+    fn key_event(&self, _down: bool, _key: u32) {}
+
+    fn pointer_event(&self, _mask: u8, _x: usize, _y: usize) {}
+
+    /// The clipboard text of the viewer, Latin-1, up to 1 MiB.
+    fn cut_text(&self, _text: &[u8]) {}
+}
+
+/// The clipboard text which the application sends to the viewers by ServerCutText, Latin-1.
+#[derive(Clone, Default)]
+pub struct Clipboard {
+    // the text and # of the changes.
+    shared: sync::Arc<sync::Mutex<(Vec<u8>, u64)>>,
+}
+
+impl Clipboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_text(&self, text: &[u8]) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.0 = text.to_vec();
+        shared.1 += 1;
+    }
+
+    // the text if it has changed since `serial`.
+    fn changed(&self, serial: &mut u64) -> Option<Vec<u8>> {
+        let shared = self.shared.lock().unwrap();
+        if shared.1 == *serial {
+            return None;
+        }
+        *serial = shared.1;
+        Some(shared.0.clone())
+    }
+}
+
+struct NullHandler;
+
+impl Handler for NullHandler {}
+
+#[derive(Default)]
+struct Client {
+    encodings: Vec<i32>,
+    format: encoder::PixelFormat,
+    // SetEncodings or SetPixelFormat which is not yet applied to the encoder.
+    options_changed: bool,
+    // the first update waits for a request, so that the pixel format and the encodings set before it apply.
+    update_requested: bool,
+    // the reader has stopped.
+    closed: bool,
+    // a SetDesktopSize request which is to be answered.
+    desktop_size_requested: bool,
+}
+
+impl Client {
+    fn supports(&self, encoding: i32) -> bool {
+        self.encodings.contains(&encoding)
+    }
+}
+
+// the time when each tile was last sent lossy.
+struct LossyMap {
+    w: usize,
+    h: usize,
+    tiles: Vec<Option<time::Instant>>,
+}
+
+impl LossyMap {
+    const TILE: usize = 64;
+    const MAX_W: usize = 2048;
+
+    fn new(w: usize, h: usize) -> Self {
+        let tw = (w + Self::TILE - 1) / Self::TILE;
+        let th = (h + Self::TILE - 1) / Self::TILE;
+        LossyMap {
+            w,
+            h,
+            tiles: vec![None; tw * th],
+        }
+    }
+
+    fn any(&self, x0: usize, y0: usize, x1: usize, y1: usize) -> bool {
+        let tw = (self.w + Self::TILE - 1) / Self::TILE;
+        (y0 / Self::TILE..(y1 + Self::TILE - 1) / Self::TILE).any(|ty| {
+            (x0 / Self::TILE..(x1 + Self::TILE - 1) / Self::TILE).any(|tx| self.tiles[tw * ty + tx].is_some())
+        })
+    }
+
+    fn mark(&mut self, x0: usize, y0: usize, x1: usize, y1: usize, now: time::Instant) {
+        let tw = (self.w + Self::TILE - 1) / Self::TILE;
+        for ty in y0 / Self::TILE..(y1 + Self::TILE - 1) / Self::TILE {
+            for tx in x0 / Self::TILE..(x1 + Self::TILE - 1) / Self::TILE {
+                self.tiles[tw * ty + tx] = Some(now);
+            }
+        }
+    }
+
+    /// Takes the rows of tiles which have been sent lossy at least `delay` ago, up to about `max_pixels`.  The tiles
+    /// stay queued unless `callback` returns that it has sent them.
+    fn take_due<F: FnMut(usize, usize, usize, usize) -> bool>(
+        &mut self,
+        now: time::Instant,
+        delay: time::Duration,
+        max_pixels: usize,
+        mut callback: F,
+    ) {
+        let tw = (self.w + Self::TILE - 1) / Self::TILE;
+        let th = (self.h + Self::TILE - 1) / Self::TILE;
+        let mut n_pixels = 0;
+        for ty in 0..th {
+            let mut tx = 0;
+            while tx < tw {
+                let due = |t: Option<time::Instant>| t.map_or(false, |t| now - t >= delay);
+                if !due(self.tiles[tw * ty + tx]) {
+                    tx += 1;
+                    continue;
+                }
+                let start = tx;
+                while tx < tw && (tx - start) * Self::TILE < Self::MAX_W && due(self.tiles[tw * ty + tx]) {
+                    tx += 1;
+                }
+                let x0 = start * Self::TILE;
+                let y0 = ty * Self::TILE;
+                let x1 = cmp::min(tx * Self::TILE, self.w);
+                let y1 = cmp::min(y0 + Self::TILE, self.h);
+                if callback(x0, y0, x1, y1) {
+                    for t in self.tiles[tw * ty + start..tw * ty + tx].iter_mut() {
+                        *t = None;
+                    }
+                }
+                n_pixels += (x1 - x0) * (y1 - y0);
+                if n_pixels >= max_pixels {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// the changes of each tile in the recent frames, a bit per frame, to find the areas playing videos.
+struct ActivityMap {
+    w: usize,
+    h: usize,
+    tiles: Vec<u32>,
+    component: Vec<usize>,
+}
+
+impl ActivityMap {
+    const TILE: usize = 64;
+    // of the last 32 frames.  Tiles already in the video region need less, so that it does not flicker.
+    const MIN_CHANGES: u32 = 20;
+    const MIN_CHANGES_IN_REGION: u32 = 12;
+    const MIN_TILES: usize = 12;
+
+    fn new(w: usize, h: usize) -> Self {
+        let tw = (w + Self::TILE - 1) / Self::TILE;
+        let th = (h + Self::TILE - 1) / Self::TILE;
+        ActivityMap {
+            w,
+            h,
+            tiles: vec![0; tw * th],
+            component: Vec::new(),
+        }
+    }
+
+    fn next_frame(&mut self) {
+        for t in self.tiles.iter_mut() {
+            *t <<= 1;
+        }
+    }
+
+    fn mark(&mut self, x0: usize, y0: usize, x1: usize, y1: usize) {
+        let tw = (self.w + Self::TILE - 1) / Self::TILE;
+        for ty in y0 / Self::TILE..(y1 + Self::TILE - 1) / Self::TILE {
+            for tx in x0 / Self::TILE..(x1 + Self::TILE - 1) / Self::TILE {
+                self.tiles[tw * ty + tx] |= 1;
+            }
+        }
+    }
+
+    /// The bounding box of the largest connected area of the tiles which have changed on most frames.
+    fn video_region(&mut self, current: Option<(usize, usize, usize, usize)>) -> Option<(usize, usize, usize, usize)> {
+        let tw = (self.w + Self::TILE - 1) / Self::TILE;
+        let th = (self.h + Self::TILE - 1) / Self::TILE;
+        let (cx0, cy0, cx1, cy1) = current.unwrap_or((0, 0, 0, 0));
+        let active = |tiles: &[u32], tx: usize, ty: usize| {
+            let (x, y) = (tx * Self::TILE, ty * Self::TILE);
+            let min = if cx0 <= x && x < cx1 && cy0 <= y && y < cy1 {
+                Self::MIN_CHANGES_IN_REGION
+            } else {
+                Self::MIN_CHANGES
+            };
+            tiles[tw * ty + tx].count_ones() >= min
+        };
+
+        let mut visited = vec![false; tw * th];
+        let mut best: Option<(usize, (usize, usize, usize, usize))> = None;
+        for i in 0..tw * th {
+            if visited[i] || !active(&self.tiles, i % tw, i / tw) {
+                continue;
+            }
+            // flood fill.
+            let mut n = 0;
+            let mut bbox = (tw, th, 0, 0);
+            self.component.clear();
+            self.component.push(i);
+            visited[i] = true;
+            while let Some(j) = self.component.pop() {
+                let (tx, ty) = (j % tw, j / tw);
+                n += 1;
+                bbox = (
+                    cmp::min(bbox.0, tx),
+                    cmp::min(bbox.1, ty),
+                    cmp::max(bbox.2, tx + 1),
+                    cmp::max(bbox.3, ty + 1),
+                );
+                let neighbours = [
+                    (tx > 0, j.wrapping_sub(1)),
+                    (tx + 1 < tw, j + 1),
+                    (ty > 0, j.wrapping_sub(tw)),
+                    (ty + 1 < th, j + tw),
+                ];
+                for &(inside, k) in neighbours.iter() {
+                    if inside && !visited[k] && active(&self.tiles, k % tw, k / tw) {
+                        visited[k] = true;
+                        self.component.push(k);
+                    }
+                }
+            }
+            if best.map_or(true, |(m, _)| n > m) {
+                best = Some((n, bbox));
+            }
+        }
+
+        let (n, (tx0, ty0, tx1, ty1)) = best?;
+        if n < Self::MIN_TILES {
+            return None;
+        }
+        Some((
+            tx0 * Self::TILE,
+            ty0 * Self::TILE,
+            cmp::min(tx1 * Self::TILE, self.w),
+            cmp::min(ty1 * Self::TILE, self.h),
+        ))
+    }
+}
+
+// estimates the throughput from the throttling: decreases when throttled, increases otherwise.
+struct RateControl {
+    start: time::Instant,
+    bytes: usize,
+    throttled: bool,
+    bitrate: f64,
+}
+
+impl RateControl {
+    const WINDOW: time::Duration = time::Duration::from_secs(1);
+    const MIN_BITRATE: f64 = 250_000.0;
+    const MAX_BITRATE: f64 = 100_000_000.0;
+
+    fn new() -> Self {
+        RateControl {
+            start: time::Instant::now(),
+            bytes: 0,
+            throttled: false,
+            bitrate: 8_000_000.0,
+        }
+    }
+
+    // returns the new bit rate at the end of each window.
+    fn sent(&mut self, bytes: usize, throttled: bool) -> Option<u64> {
+        self.bytes += bytes;
+        self.throttled |= throttled;
+        let elapsed = self.start.elapsed();
+        if elapsed < Self::WINDOW {
+            return None;
+        }
+
+        let achieved = (8 * self.bytes) as f64 / elapsed.as_secs_f64();
+        self.bitrate = if self.throttled {
+            0.9 * achieved
+        } else if 2.0 * achieved >= self.bitrate {
+            f64::max(1.25 * self.bitrate, achieved)
+        } else {
+            // an idle link tells nothing about its capacity.
+            self.bitrate
+        };
+        self.bitrate = f64::max(Self::MIN_BITRATE, f64::min(Self::MAX_BITRATE, self.bitrate));
+        self.start = time::Instant::now();
+        self.bytes = 0;
+        self.throttled = false;
+        Some(self.bitrate as u64)
+    }
+}
+
+// the rectangles of a frame which a worker encodes, with their pixels packed, and the results.
+#[derive(Default)]
+struct Job {
+    options: Option<encoder::Options>,
+    rects: Vec<(usize, usize, usize, usize)>,
+    pixels: Vec<u32>,
+    out: Vec<u8>,
+    // the end in `out`, # of the rectangles written and whether they may be lossy, for each rectangle.
+    results: Vec<(usize, usize, bool)>,
+}
+
+impl Job {
+    // encodes the rectangles from `screen` of `stride`, or from `pixels` if None.
+    fn run<E: encoder::Encoder>(&mut self, encoder: &mut E, screen: Option<(&[u32], usize)>) {
+        self.out.clear();
+        self.results.clear();
+        let mut offset = 0;
+        for &(x0, y0, x1, y1) in self.rects.iter() {
+            let (w, h) = (x1 - x0, y1 - y0);
+            let (screen, stride) = match screen {
+                Some((screen, stride)) => (&screen[stride * y0 + x0..], stride),
+                None => (&self.pixels[offset..], w),
+            };
+            offset += w * h;
+            let n = encoder.encode_rect(&mut self.out, screen, stride, x0, y0, w, h);
+            self.results.push((self.out.len(), n, encoder.take_lossy()));
+        }
+    }
+}
+
+struct Worker {
+    jobs: sync::mpsc::Sender<Job>,
+    done: sync::mpsc::Receiver<Job>,
+    // the buffers while the worker is idle.
+    job: Option<Job>,
+}
+
+// encodes the rectangles of a frame by several instances of the encoder in parallel.  The encoder of the write thread
+// is the first worker and each of the others has its own thread.  Each instance is pinned to its share of the state
+// of the connection, e.g. a zlib stream, so the order of the rectangles only matters within an instance.
+struct EncoderPool<E: encoder::Encoder> {
+    workers: Vec<Worker>,
+    local: Job,
+    options: Option<encoder::Options>,
+    // the rectangles in order and their workers.
+    assigned: Vec<((usize, usize, usize, usize), usize)>,
+    _encoder: marker::PhantomData<E>,
+}
+
+impl<E: encoder::Encoder + 'static> EncoderPool<E> {
+    // smaller frames are encoded by the write thread alone.
+    const MIN_PARALLEL_PIXELS: usize = 1 << 16;
+    const MIN_BAND_PIXELS: usize = 1 << 14;
+
+    // `encoder` is the first worker of `n`.
+    fn new(encoder: &mut E, n: usize) -> Self {
+        if n > 1 {
+            encoder.pin(0);
+        }
+        let workers = (1..n)
+            .map(|i| {
+                let (jobs, jobs_rx) = sync::mpsc::channel();
+                let (done_tx, done) = sync::mpsc::channel();
+                thread::spawn(move || Self::work(i, jobs_rx, done_tx));
+                Worker {
+                    jobs,
+                    done,
+                    job: Some(Job::default()),
+                }
+            })
+            .collect();
+        EncoderPool {
+            workers,
+            local: Job::default(),
+            options: None,
+            assigned: Vec::new(),
+            _encoder: marker::PhantomData,
+        }
+    }
+
+    // ends when the pool is dropped.
+    fn work(i: usize, jobs: sync::mpsc::Receiver<Job>, done: sync::mpsc::Sender<Job>) {
+        let mut encoder = E::new();
+        encoder.pin(i);
+        let mut options = None;
+        for mut job in jobs.iter() {
+            if job.options != options {
+                options = job.options;
+                if let Some(options) = options {
+                    encoder.set_options(&options);
+                }
+            }
+            job.run(&mut encoder, None);
+            if done.send(job).is_err() {
+                break;
+            }
+        }
+    }
+
+    // the options are passed to the other workers with their next jobs.
+    fn set_options(&mut self, encoder: &mut E, options: &encoder::Options) {
+        encoder.set_options(options);
+        self.options = Some(*options);
+    }
+
+    // encodes `rects` (x0, y0, x1, y1) and appends them to `buf` in order.  `lossy` is called for the rectangles
+    // which may be lossy.  Returns # of the rectangles written.
+    fn encode<F: FnMut(usize, usize, usize, usize)>(
+        &mut self,
+        encoder: &mut E,
+        buf: &mut Vec<u8>,
+        screen: &[u32],
+        stride: usize,
+        rects: &[(usize, usize, usize, usize)],
+        mut lossy: F,
+    ) -> io::Result<usize> {
+        let n_pixels: usize = rects.iter().map(|&(x0, y0, x1, y1)| (x1 - x0) * (y1 - y0)).sum();
+        let n_workers = self.workers.len() + 1;
+        if n_workers == 1 || n_pixels < Self::MIN_PARALLEL_PIXELS {
+            let mut n_rects = 0;
+            for &(x0, y0, x1, y1) in rects.iter() {
+                n_rects += encoder.encode_rect(buf, &screen[stride * y0 + x0..], stride, x0, y0, x1 - x0, y1 - y0);
+                if encoder.take_lossy() {
+                    lossy(x0, y0, x1, y1);
+                }
+            }
+            return Ok(n_rects);
+        }
+
+        // large rectangles are cut into bands, and each one goes to the least loaded worker.
+        let max_band = cmp::max(Self::MIN_BAND_PIXELS, n_pixels / (2 * n_workers));
+        let mut loads = vec![0; n_workers];
+        self.assigned.clear();
+        for &(x0, y0, x1, y1) in rects.iter() {
+            let bh = cmp::max(1, max_band / (x1 - x0));
+            for by in (y0..y1).step_by(bh) {
+                let rect = (x0, by, x1, cmp::min(by + bh, y1));
+                let (i, _) = loads.iter().enumerate().min_by_key(|&(_, &load)| load).unwrap();
+                loads[i] += (rect.2 - rect.0) * (rect.3 - rect.1);
+                self.assigned.push((rect, i));
+            }
+        }
+
+        for (i, worker) in self.workers.iter_mut().enumerate() {
+            let mut job = worker.job.take().unwrap();
+            job.options = self.options;
+            job.rects.clear();
+            job.pixels.clear();
+            for &((x0, y0, x1, y1), _) in self.assigned.iter().filter(|&&(_, j)| j == i + 1) {
+                job.rects.push((x0, y0, x1, y1));
+                for y in y0..y1 {
+                    job.pixels.extend_from_slice(&screen[stride * y + x0..stride * y + x1]);
+                }
+            }
+            if worker.jobs.send(job).is_err() {
+                return Err(io::Error::new(io::ErrorKind::Other, "encoder worker"));
+            }
+        }
+        self.local.rects.clear();
+        for &(rect, _) in self.assigned.iter().filter(|&&(_, j)| j == 0) {
+            self.local.rects.push(rect);
+        }
+        self.local.run(encoder, Some((screen, stride)));
+        for worker in self.workers.iter_mut() {
+            match worker.done.recv() {
+                Ok(job) => worker.job = Some(job),
+                Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "encoder worker")),
+            }
+        }
+
+        // assemble the results in the order of the rectangles.
+        let mut n_rects = 0;
+        let mut next = vec![(0, 0); n_workers];
+        for &(rect, i) in self.assigned.iter() {
+            let job = if i == 0 {
+                &self.local
+            } else {
+                self.workers[i - 1].job.as_ref().unwrap()
+            };
+            let (start, k) = next[i];
+            let (end, n, is_lossy) = job.results[k];
+            buf.extend_from_slice(&job.out[start..end]);
+            n_rects += n;
+            if is_lossy {
+                lossy(rect.0, rect.1, rect.2, rect.3);
+            }
+            next[i] = (end, k + 1);
+        }
+        Ok(n_rects)
+    }
+}
+
+// of the rectangles (x0, y0, x1, y1), or None if there are none.
+fn bounding_box(rects: &[(usize, usize, usize, usize)]) -> Option<(usize, usize, usize, usize)> {
+    if rects.is_empty() {
+        return None;
+    }
+    Some(rects.iter().fold(
+        (usize::MAX, usize::MAX, 0, 0),
+        |(x0, y0, x1, y1), &(rx0, ry0, rx1, ry1)| {
+            (
+                cmp::min(x0, rx0),
+                cmp::min(y0, ry0),
+                cmp::max(x1, rx1),
+                cmp::max(y1, ry1),
+            )
+        },
+    ))
+}
+
+type SourceFactory = dyn Fn() -> io::Result<Box<dyn capture::Source>> + Send + Sync;
+
+pub struct Builder<Comparator: comparator::Comparator, Encoder: encoder::Encoder> {
+    name: String,
+    source: Box<SourceFactory>,
+    handler: sync::Arc<dyn Handler>,
+    clipboard: Clipboard,
+    refine_delay: Option<time::Duration>,
+    video_interval: Option<time::Duration>,
+    workers: usize,
+    _comparator: marker::PhantomData<Comparator>,
+    _encoder: marker::PhantomData<Encoder>,
+}
+
+impl<Comparator: comparator::Comparator, Encoder: encoder::Encoder> Builder<Comparator, Encoder> {
+    pub fn new() -> Self {
+        Builder {
+            name: "mfxvnc".into(),
+            source: Box::new(capture::default_source),
+            handler: sync::Arc::new(NullHandler),
+            clipboard: Clipboard::new(),
+            refine_delay: Some(time::Duration::from_millis(500)),
+            video_interval: Some(time::Duration::from_millis(40)),
+            workers: 1,
+            _comparator: marker::PhantomData,
+            _encoder: marker::PhantomData,
+        }
+    }
+
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn source<F>(mut self, source: F) -> Self
+    where
+        F: Fn() -> io::Result<Box<dyn capture::Source>> + Send + Sync + 'static,
+    {
+        self.source = Box::new(source);
+        self
+    }
+
+    /// Wraps the current source, e.g. by `capture::Crop`.
+    pub fn map_source<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Box<dyn capture::Source>) -> Box<dyn capture::Source> + Send + Sync + 'static,
+    {
+        let source = self.source;
+        self.source = Box::new(move || Ok(f(source()?)));
+        self
+    }
+
+    pub fn framebuffer(self, framebuffer: &framebuffer::Framebuffer) -> Self {
+        let framebuffer = framebuffer.clone();
+        self.source(move || Ok(Box::new(framebuffer.source())))
+    }
+
+    pub fn handler<H: Handler + 'static>(mut self, handler: H) -> Self {
+        self.handler = sync::Arc::new(handler);
+        self
+    }
+
+    pub fn clipboard(mut self, clipboard: &Clipboard) -> Self {
+        self.clipboard = clipboard.clone();
+        self
+    }
+
+    /// Re-sends areas sent lossy losslessly once they have been unchanged for `delay` and the connection is idle.
+    /// `None` disables it.
+    pub fn refine(mut self, delay: Option<time::Duration>) -> Self {
+        self.refine_delay = delay;
+        self
+    }
+
+    /// Sends the areas which change on most frames, e.g. video players, by `Encoder::encode_video_rect()` at most
+    /// once per `interval`.  `None` disables the detection.
+    pub fn video(mut self, interval: Option<time::Duration>) -> Self {
+        self.video_interval = interval;
+        self
+    }
+
+    /// The number of threads which encode the rectangles of a frame in parallel, including the one of the
+    /// connection.  It is also limited by `Encoder::max_workers()`.  Each instance of an encoder with several zlib
+    /// streams, e.g. Tight, is then pinned to one stream rather than choosing it by the content.  Defaults to 1.
+    pub fn workers(mut self, n: usize) -> Self {
+        self.workers = cmp::max(1, n);
+        self
+    }
+
+    pub fn build(self) -> VncServer<Comparator, Encoder> {
+        VncServer {
+            name: self.name,
+            source: self.source,
+            handler: self.handler,
+            clipboard: self.clipboard,
+            refine_delay: self.refine_delay,
+            video_interval: self.video_interval,
+            workers: self.workers,
+            _comparator: marker::PhantomData,
+            _encoder: marker::PhantomData,
+        }
+    }
+}
+
 pub struct VncServer<Comparator: comparator::Comparator, Encoder: encoder::Encoder> {
+    name: String,
+    source: Box<SourceFactory>,
+    handler: sync::Arc<dyn Handler>,
+    clipboard: Clipboard,
+    refine_delay: Option<time::Duration>,
+    video_interval: Option<time::Duration>,
+    workers: usize,
     _comparator: marker::PhantomData<Comparator>,
     _encoder: marker::PhantomData<Encoder>,
 }
 
-impl<Comparator: comparator::Comparator, Encoder: encoder::Encoder> VncServer<Comparator, Encoder> {
-    pub fn listen<A: net::ToSocketAddrs>(addr: A) -> io::Result<()> {
+impl<Comparator: comparator::Comparator, Encoder: encoder::Encoder + 'static> VncServer<Comparator, Encoder> {
+    const COPY_RECT: i32 = 1;
+    const DESKTOP_SIZE: i32 = -223;
+    const LAST_RECT: i32 = -224;
+    const EXTENDED_DESKTOP_SIZE: i32 = -308;
+    const MAX_CUT_TEXT: usize = 1 << 20;
+
+    pub fn builder() -> Builder<Comparator, Encoder> {
+        Builder::new()
+    }
+
+    pub fn listen<A: net::ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
         let listener = net::TcpListener::bind(addr)?;
         for stream in listener.incoming() {
             let mut stream = stream?;
             stream.set_nodelay(true)?;
-            if let Err(_) = Self::shake_hands(&mut stream) {
+            if let Err(_) = self.shake_hands(&mut stream) {
                 continue;
             }
 
+            let client = sync::Arc::new(sync::Mutex::new(Client::default()));
             let reader = {
-                let stream = stream.try_clone()?;
-                thread::spawn(move || Self::read_loop(stream))
+                let mut stream = stream.try_clone()?;
+                let handler = self.handler.clone();
+                let client = client.clone();
+                thread::spawn(move || {
+                    let result = Self::read_loop(&mut stream, handler, client.clone());
+                    // stop the writer if the reader has failed.
+                    client.lock().unwrap().closed = true;
+                    stream.shutdown(net::Shutdown::Both).ok();
+                    result
+                })
             };
-            let w_result = Self::write_loop(stream);
+            let w_result = self.write_loop(stream.try_clone()?, client);
+            // wake the reader up if the writer has failed.
+            stream.shutdown(net::Shutdown::Both).ok();
             let r_result = reader.join().unwrap();
             w_result.and(r_result)?
         }
         Ok(())
     }
 
-    fn shake_hands(stream: &mut net::TcpStream) -> io::Result<()> {
+    fn shake_hands(&self, stream: &mut net::TcpStream) -> io::Result<()> {
         // => protocol version.
         stream.write_all(b"RFB 003.008\n")?;
         // <= protocol version.
@@ -51,6 +696,11 @@ impl<Comparator: comparator::Comparator, Encoder: encoder::Encoder> VncServer<Co
         }
 
         // security result.
+        if !self.handler.authenticate(stream.peer_addr()?) {
+            stream.write_u32::<BigEndian>(1)?;
+            stream.write_all(b"\x00\x00\x00\x0edenied access.")?;
+            return Err(io::Error::new(io::ErrorKind::Other, "authentication"));
+        }
         stream.write_u32::<BigEndian>(0)?;
 
         // client init.
@@ -61,18 +711,87 @@ impl<Comparator: comparator::Comparator, Encoder: encoder::Encoder> VncServer<Co
         Ok(())
     }
 
-    fn read_loop(mut stream: net::TcpStream) -> io::Result<()> {
-        let mut buf = [0; 4096];
+    fn read_loop(
+        stream: &mut net::TcpStream,
+        handler: sync::Arc<dyn Handler>,
+        client: sync::Arc<sync::Mutex<Client>>,
+    ) -> io::Result<()> {
         loop {
-            stream.read(&mut buf)?;
+            match stream.read_u8()? {
+                // set pixel format.
+                0 => {
+                    let mut buf = [0; 3];
+                    stream.read_exact(&mut buf)?; // padding.
+                    let mut format = [0; 16];
+                    stream.read_exact(&mut format)?;
+                    let mut client = client.lock().unwrap();
+                    client.format = encoder::PixelFormat::from_bytes(&format);
+                    client.options_changed = true;
+                }
+                // set encodings.
+                2 => {
+                    stream.read_u8()?; // padding.
+                    let n = stream.read_u16::<BigEndian>()?;
+                    let mut encodings = Vec::with_capacity(n as usize);
+                    for _ in 0..n {
+                        encodings.push(stream.read_i32::<BigEndian>()?);
+                    }
+                    let mut client = client.lock().unwrap();
+                    client.encodings = encodings;
+                    client.options_changed = true;
+                }
+                // framebuffer update request.
+                3 => {
+                    let mut buf = [0; 9];
+                    stream.read_exact(&mut buf)?;
+                    client.lock().unwrap().update_requested = true;
+                }
+                // key event.
+                4 => {
+                    let down = stream.read_u8()? != 0;
+                    stream.read_u16::<BigEndian>()?; // padding.
+                    let key = stream.read_u32::<BigEndian>()?;
+                    handler.key_event(down, key);
+                }
+                // pointer event.
+                5 => {
+                    let mask = stream.read_u8()?;
+                    let x = stream.read_u16::<BigEndian>()?;
+                    let y = stream.read_u16::<BigEndian>()?;
+                    handler.pointer_event(mask, x as usize, y as usize);
+                }
+                // client cut text.
+                6 => {
+                    let mut buf = [0; 3];
+                    stream.read_exact(&mut buf)?; // padding.
+                    let len = stream.read_u32::<BigEndian>()? as usize;
+                    // the rest of a longer text is skipped, not allocated.
+                    let mut text = vec![0; cmp::min(len, Self::MAX_CUT_TEXT)];
+                    stream.read_exact(&mut text)?;
+                    io::copy(&mut (&mut *stream).take((len - text.len()) as u64), &mut io::sink())?;
+                    handler.cut_text(&text);
+                }
+                // set desktop size.
+                251 => {
+                    let mut buf = [0; 7];
+                    stream.read_exact(&mut buf)?; // padding, width, height, # of screens, padding.
+                    let mut screens = vec![0; 16 * buf[5] as usize];
+                    stream.read_exact(&mut screens)?;
+                    // resizing by clients is not supported.
+                    client.lock().unwrap().desktop_size_requested = true;
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::Other, "message type")),
+            }
         }
     }
 
-    fn write_loop(mut stream: net::TcpStream) -> io::Result<()> {
+    fn write_loop(&self, mut stream: net::TcpStream, client: sync::Arc<sync::Mutex<Client>>) -> io::Result<()> {
         let mut encoder = Encoder::new();
-        let mut cap = scrap::Capturer::new(scrap::Display::primary()?)?;
-        let w = cap.width();
-        let h = cap.height();
+        let n_workers = cmp::min(self.workers, encoder.max_workers());
+        let mut pool = EncoderPool::new(&mut encoder, n_workers);
+        let mut source = (self.source)()?;
+        let mut w = source.width();
+        let mut h = source.height();
         let mut buf = Vec::with_capacity(w * h * 4);
 
         /* send a server init message. */
@@ -90,14 +809,50 @@ impl<Comparator: comparator::Comparator, Encoder: encoder::Encoder> VncServer<Co
             buf.write_u8(8)?; // G shift.
             buf.write_u8(16)?; // B shift.
             buf.write_all(&[0; 3])?; // padding.
-            let name = b"mfxvnc";
+            let name = self.name.as_bytes();
             buf.write_u32::<BigEndian>(name.len() as u32)?;
             buf.write_all(name)?;
             stream.write_all(&buf)?;
         }
 
+        // wait for the first request.
+        loop {
+            {
+                let client = client.lock().unwrap();
+                if client.update_requested {
+                    break;
+                }
+                if client.closed {
+                    return Ok(());
+                }
+            }
+            thread::sleep(time::Duration::from_secs(1) / 120);
+        }
+
         let mut prev_screen = Vec::new();
+        let mut stride = w;
+        let mut lossy = LossyMap::new(0, 0);
+        let mut motion = motion::MotionDetector::new();
+        let mut rate = RateControl::new();
+        let mut activity = ActivityMap::new(0, 0);
+        let mut video_region = None;
+        let mut video_pending = false;
+        let mut video_sent = time::Instant::now();
+        let mut converted = Vec::new();
+        let mut sent_screens = Vec::new();
+        let mut rects = Vec::new();
+        let mut clipboard_serial = 0;
         loop {
+            // server cut text.
+            if let Some(text) = self.clipboard.changed(&mut clipboard_serial) {
+                let mut message = Vec::with_capacity(8 + text.len());
+                message.write_u8(3)?; // message type: server cut text.
+                message.write_all(&[0; 3])?; // padding.
+                message.write_u32::<BigEndian>(text.len() as u32)?;
+                message.write_all(&text)?;
+                stream.write_all(&message)?;
+            }
+
             let prev_buf_len = buf.len();
             buf.clear();
 
@@ -109,35 +864,217 @@ impl<Comparator: comparator::Comparator, Encoder: encoder::Encoder> VncServer<Co
 
             let n_rects = {
                 // capture.
-                let next_screen = match cap.frame() {
-                    Ok(buf) => buf,
+                let screens = source.screens();
+                let frame = match source.frame() {
+                    Ok(frame) => frame.to_bgrx(&mut converted),
                     Err(err) => {
                         if err.kind() == io::ErrorKind::WouldBlock {
-                            thread::sleep(time::Duration::from_secs(1) / 120);
+                            let n_rects =
+                                self.refine(&stream, &mut encoder, &mut lossy, &prev_screen, stride, &mut buf);
+                            if n_rects > 0 {
+                                Self::write_n_rects(&mut buf, n_rects_index, n_rects, &client)?;
+                                stream.write_all(&buf)?;
+                            }
                             continue;
                         } else {
                             return Err(err.into());
                         }
                     }
                 };
-                let next_screen =
-                    unsafe { slice::from_raw_parts(next_screen.as_ptr() as *const u32, next_screen.len() / 4) };
+                let next_screen = frame.data;
+                let mut n_rects = 0;
+                let resized = frame.width != w || frame.height != h;
+                let (desktop_size, extended, copy_rect, requested) = {
+                    let mut client = client.lock().unwrap();
+                    let requested = mem::replace(&mut client.desktop_size_requested, false);
+                    if mem::replace(&mut client.options_changed, false) {
+                        if !encoder.supports_format(&client.format) {
+                            return Err(io::Error::new(io::ErrorKind::Other, "pixel format"));
+                        }
+                        let options = encoder::Options {
+                            format: client.format,
+                            ..encoder::Options::from_encodings(&client.encodings)
+                        };
+                        pool.set_options(&mut encoder, &options);
+                    }
+                    (
+                        client.supports(Self::DESKTOP_SIZE),
+                        client.supports(Self::EXTENDED_DESKTOP_SIZE),
+                        client.supports(Self::COPY_RECT),
+                        requested,
+                    )
+                };
+                w = frame.width;
+                h = frame.height;
+                if extended && (resized || requested || screens != sent_screens) {
+                    // reason: server or client, status: no error or prohibited.
+                    let (reason, status) = if requested { (1, 1) } else { (0, 0) };
+                    buf.write_u16::<BigEndian>(reason)?;
+                    buf.write_u16::<BigEndian>(status)?;
+                    buf.write_u16::<BigEndian>(w as u16)?;
+                    buf.write_u16::<BigEndian>(h as u16)?;
+                    buf.write_i32::<BigEndian>(Self::EXTENDED_DESKTOP_SIZE)?;
+                    buf.write_u8(screens.len() as u8)?;
+                    buf.write_all(&[0; 3])?; // padding.
+                    for screen in screens.iter() {
+                        buf.write_u32::<BigEndian>(screen.id)?;
+                        buf.write_u16::<BigEndian>(screen.x as u16)?;
+                        buf.write_u16::<BigEndian>(screen.y as u16)?;
+                        buf.write_u16::<BigEndian>(screen.w as u16)?;
+                        buf.write_u16::<BigEndian>(screen.h as u16)?;
+                        buf.write_u32::<BigEndian>(0)?; // flags.
+                    }
+                    n_rects += 1;
+                    sent_screens = screens;
+                } else if resized {
+                    if !desktop_size {
+                        return Err(io::Error::new(io::ErrorKind::Other, "desktop size"));
+                    }
+                    buf.write_u16::<BigEndian>(0)?;
+                    buf.write_u16::<BigEndian>(0)?;
+                    buf.write_u16::<BigEndian>(w as u16)?;
+                    buf.write_u16::<BigEndian>(h as u16)?;
+                    buf.write_i32::<BigEndian>(Self::DESKTOP_SIZE)?;
+                    n_rects += 1;
+                }
+                if resized {
+                    // the contents of the client are undefined after resizing.
+                    prev_screen = vec![!0; next_screen.len()];
+                }
                 if next_screen.len() != prev_screen.len() {
                     prev_screen = vec![0; next_screen.len()];
                 }
-                let stride = next_screen.len() / h;
+                if resized || lossy.w != w || lossy.h != h {
+                    lossy = LossyMap::new(w, h);
+                }
+                if resized || activity.w != w || activity.h != h {
+                    activity = ActivityMap::new(w, h);
+                    video_region = None;
+                    video_pending = false;
+                }
+                stride = frame.stride;
 
                 // search & encode update region.
                 let timer = time::SystemTime::now();
-                let mut n_rects = 0;
-                Comparator::compare(&mut prev_screen, &next_screen, stride, w, h, |x0, y0, x1, y1| {
-                    buf.write_u16::<BigEndian>(x0 as u16).unwrap();
-                    buf.write_u16::<BigEndian>(y0 as u16).unwrap();
-                    buf.write_u16::<BigEndian>((x1 - x0) as u16).unwrap();
-                    buf.write_u16::<BigEndian>((y1 - y0) as u16).unwrap();
-                    encoder.encode(&mut buf, &next_screen[stride * y0 + x0..], stride, x1 - x0, y1 - y0);
-                    n_rects += 1;
-                });
+                let now = time::Instant::now();
+
+                // moved areas are copied on the client before the residual damage.
+                let region = match frame.damage {
+                    capture::Damage::Unknown => Some((0, 0, w, h)),
+                    capture::Damage::Hints(damage) | capture::Damage::Exact(damage) => bounding_box(damage),
+                };
+                // the client does not have the video region yet if it is pending.
+                let stale = |m: &motion::Motion| {
+                    let (sx, sy) = (m.src_x(), m.src_y());
+                    video_pending
+                        && video_region.map_or(false, |(x0, y0, x1, y1)| {
+                            sx < x1 && x0 < sx + m.x1 - m.x0 && sy < y1 && y0 < sy + m.y1 - m.y0
+                        })
+                };
+                let mut moved = false;
+                if let Some(region) = region.filter(|_| copy_rect && !resized) {
+                    let m = motion.detect(&prev_screen, &next_screen, stride, w, h, region, video_region);
+                    if let Some(m) = m.filter(|m| !stale(m)) {
+                        moved = true;
+                        buf.write_u16::<BigEndian>(m.x0 as u16)?;
+                        buf.write_u16::<BigEndian>(m.y0 as u16)?;
+                        buf.write_u16::<BigEndian>((m.x1 - m.x0) as u16)?;
+                        buf.write_u16::<BigEndian>((m.y1 - m.y0) as u16)?;
+                        buf.write_i32::<BigEndian>(Self::COPY_RECT)?;
+                        buf.write_u16::<BigEndian>(m.src_x() as u16)?;
+                        buf.write_u16::<BigEndian>(m.src_y() as u16)?;
+                        n_rects += 1;
+                        m.apply(&mut prev_screen, stride);
+                        let (sx, sy) = (m.src_x(), m.src_y());
+                        if lossy.any(sx, sy, sx + m.x1 - m.x0, sy + m.y1 - m.y0) {
+                            lossy.mark(m.x0, m.y0, m.x1, m.y1, now);
+                        }
+                    }
+                }
+                // areas playing videos are sent at a reduced frame rate.
+                let mut flush = None;
+                if self.video_interval.is_some() {
+                    let region = activity.video_region(video_region);
+                    if region != video_region {
+                        if mem::replace(&mut video_pending, false) {
+                            flush = video_region;
+                        }
+                        video_region = region;
+                    }
+                }
+                activity.next_frame();
+
+                // the damage is tiled to the limits of the encoder, whichever comparator is used, and encoded at once.
+                let limits = encoder.limits();
+                rects.clear();
+                let mut emit = |x0: usize, y0: usize, x1: usize, y1: usize| {
+                    activity.mark(x0, y0, x1, y1);
+                    if let Some((vx0, vy0, vx1, vy1)) = video_region {
+                        if vx0 <= x0 && vy0 <= y0 && x1 <= vx1 && y1 <= vy1 {
+                            video_pending = true;
+                            return;
+                        }
+                    }
+                    limits.split(x0, y0, x1, y1, &mut |x0, y0, x1, y1| rects.push((x0, y0, x1, y1)));
+                };
+                match frame.damage {
+                    capture::Damage::Unknown => {
+                        Comparator::compare(&mut prev_screen, &next_screen, stride, w, h, &mut emit)
+                    }
+                    capture::Damage::Exact(damage) if !moved => {
+                        for &(x0, y0, x1, y1) in damage.iter() {
+                            for y in y0..y1 {
+                                for x in x0..x1 {
+                                    prev_screen[stride * y + x] = next_screen[stride * y + x] & 0x00ffffff;
+                                }
+                            }
+                            emit(x0, y0, x1, y1);
+                        }
+                    }
+                    // the exact damage includes the moved area, so it is compared as the hints after a move.
+                    capture::Damage::Hints(damage) | capture::Damage::Exact(damage) => {
+                        for &(x0, y0, x1, y1) in damage.iter() {
+                            let i = stride * y0 + x0;
+                            Comparator::compare(
+                                &mut prev_screen[i..],
+                                &next_screen[i..],
+                                stride,
+                                x1 - x0,
+                                y1 - y0,
+                                |rx0, ry0, rx1, ry1| emit(x0 + rx0, y0 + ry0, x0 + rx1, y0 + ry1),
+                            );
+                        }
+                    }
+                }
+                // the rest of the region which is no longer a video.
+                if let Some((x0, y0, x1, y1)) = flush {
+                    limits.split(x0, y0, x1, y1, &mut |x0, y0, x1, y1| rects.push((x0, y0, x1, y1)));
+                }
+                n_rects += pool.encode(
+                    &mut encoder,
+                    &mut buf,
+                    &next_screen,
+                    stride,
+                    &rects,
+                    |x0, y0, x1, y1| lossy.mark(x0, y0, x1, y1, now),
+                )?;
+                if let (Some((x0, y0, x1, y1)), Some(interval)) = (video_region, self.video_interval) {
+                    if video_pending && now - video_sent >= interval {
+                        video_pending = false;
+                        video_sent = now;
+                        limits.split(x0, y0, x1, y1, &mut |x0, y0, x1, y1| {
+                            let screen = &next_screen[stride * y0 + x0..];
+                            n_rects += encoder.encode_video_rect(&mut buf, screen, stride, x0, y0, x1 - x0, y1 - y0);
+                            if encoder.take_lossy() {
+                                lossy.mark(x0, y0, x1, y1, now);
+                            }
+                        });
+                    }
+                }
+                n_rects += encoder.end_frame(&mut buf, &next_screen, stride, w, h);
+                if let Some((x0, y0, x1, y1)) = encoder.take_lossy_rect() {
+                    lossy.mark(x0, y0, x1, y1, now);
+                }
                 let elapsed = timer.elapsed().unwrap();
                 eprintln!(
                     "  encode: {:>3} ms, {:>4} KiB.",
@@ -145,11 +1082,14 @@ impl<Comparator: comparator::Comparator, Encoder: encoder::Encoder> VncServer<Co
                     buf.len() / 1024,
                 );
 
+                if n_rects == 0 {
+                    n_rects = self.refine(&stream, &mut encoder, &mut lossy, &prev_screen, stride, &mut buf);
+                }
                 n_rects
             };
 
             // rewrite # of rectangles.
-            BigEndian::write_u16(&mut buf[n_rects_index..], n_rects);
+            Self::write_n_rects(&mut buf, n_rects_index, n_rects, &client)?;
 
             // send messages.
             if n_rects > 0 {
@@ -157,26 +1097,89 @@ impl<Comparator: comparator::Comparator, Encoder: encoder::Encoder> VncServer<Co
             }
 
             // throttle.
-            #[cfg(unix)]
             {
-                use libc;
-                use os::unix::io::AsRawFd;
-
                 let mut n = 0;
-                while {
-                    let mut remaining: i32 = 0;
-                    unsafe { libc::ioctl(stream.as_raw_fd(), libc::TIOCOUTQ, &mut remaining) };
-                    assert!(remaining >= 0);
-                    remaining as usize >= prev_buf_len + buf.len()
-                } {
+                while Self::unsent(&stream) >= prev_buf_len + buf.len() {
                     thread::sleep(time::Duration::from_secs(1) / 120);
                     n += 1;
                 }
                 if n > 0 {
                     eprintln!("throttle: {:>3} ms", n * 1000 / 120);
-                    //cap.frame().ok();
+                    //source.frame().ok();
+                }
+                let sent = if n_rects > 0 { buf.len() } else { 0 };
+                if let Some(bitrate) = rate.sent(sent, n > 0) {
+                    encoder.set_bitrate(bitrate);
                 }
             }
         }
     }
+
+    // writes # of the rectangles of the update at `index` of `buf`, or 0xffff and a LastRect after them if they are
+    // more.
+    fn write_n_rects(buf: &mut Vec<u8>, index: usize, n_rects: usize, client: &sync::Mutex<Client>) -> io::Result<()> {
+        if n_rects <= u16::MAX as usize {
+            BigEndian::write_u16(&mut buf[index..], n_rects as u16);
+            return Ok(());
+        }
+        if !client.lock().unwrap().supports(Self::LAST_RECT) {
+            return Err(io::Error::new(io::ErrorKind::Other, "# of rectangles"));
+        }
+        BigEndian::write_u16(&mut buf[index..], u16::MAX);
+        encoder::write_rect_header(buf, 0, 0, 0, 0);
+        buf.write_i32::<BigEndian>(Self::LAST_RECT)?;
+        Ok(())
+    }
+
+    // # of bytes not yet sent, or 0 if unknown.
+    fn unsent(stream: &net::TcpStream) -> usize {
+        #[cfg(unix)]
+        {
+            use libc;
+            use os::unix::io::AsRawFd;
+
+            let mut remaining: i32 = 0;
+            unsafe { libc::ioctl(stream.as_raw_fd(), libc::TIOCOUTQ, &mut remaining) };
+            assert!(remaining >= 0);
+            remaining as usize
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = stream;
+            0
+        }
+    }
+
+    // encodes the lossy areas which are due losslessly, only when nothing is waiting to be sent.
+    fn refine(
+        &self,
+        stream: &net::TcpStream,
+        encoder: &mut Encoder,
+        lossy: &mut LossyMap,
+        screen: &[u32],
+        stride: usize,
+        buf: &mut Vec<u8>,
+    ) -> usize {
+        const MAX_PIXELS: usize = 1 << 18;
+        let delay = match self.refine_delay {
+            Some(delay) => delay,
+            None => return 0,
+        };
+        if Self::unsent(stream) > 0 {
+            return 0;
+        }
+
+        let mut n_rects = 0;
+        let limits = encoder.limits();
+        lossy.take_due(time::Instant::now(), delay, MAX_PIXELS, |x0, y0, x1, y1| {
+            let mut n = 0;
+            limits.split(x0, y0, x1, y1, &mut |x0, y0, x1, y1| {
+                let screen = &screen[stride * y0 + x0..];
+                n += encoder.refine_rect(buf, screen, stride, x0, y0, x1 - x0, y1 - y0);
+            });
+            n_rects += n;
+            n > 0
+        });
+        n_rects
+    }
 }
@@ -0,0 +1,324 @@
+use crate::capture;
+use std::*;
+use x11rb::connection::Connection;
+use x11rb::protocol::composite::{self, ConnectionExt as _};
+use x11rb::protocol::damage::{self, ConnectionExt as _};
+use x11rb::protocol::randr::ConnectionExt as _;
+use x11rb::protocol::shm::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::{self, ConnectionExt as _};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+
+/// A capture source which reads the root window or a single window through MIT-SHM, only when XDamage reports
+/// changes.  The reported areas are passed to the comparator as hints.  A single window is redirected by XComposite
+/// so that it can be captured even if it is obscured, and its size follows the window.
+pub struct X11Source {
+    conn: RustConnection,
+    window: xproto::Window,
+    // the pixmap named by XComposite, or the root window itself.
+    drawable: xproto::Drawable,
+    redirected: bool,
+    w: usize,
+    h: usize,
+    shmseg: shm::Seg,
+    shmaddr: *mut u8,
+    shmsize: usize,
+    damage: damage::Damage,
+    rects: Vec<(usize, usize, usize, usize)>,
+    screens: Vec<capture::Screen>,
+    first: bool,
+}
+
+impl X11Source {
+    const INTERVAL: time::Duration = time::Duration::from_nanos(1_000_000_000 / 120);
+    const MAX_DAMAGE: usize = 64;
+
+    /// `display` is e.g. ":0", or `$DISPLAY` if `None`.
+    pub fn new(display: Option<&str>) -> io::Result<Self> {
+        let (conn, screen) = x11rb::connect(display).map_err(Self::error)?;
+        let root = conn.setup().roots[screen].root;
+        Self::open(conn, root, false)
+    }
+
+    /// Captures a single window, e.g. the one printed by `xwininfo`.
+    pub fn window(display: Option<&str>, window: xproto::Window) -> io::Result<Self> {
+        let (conn, _) = x11rb::connect(display).map_err(Self::error)?;
+        conn.composite_query_version(0, 4)
+            .map_err(Self::error)?
+            .reply()
+            .map_err(Self::error)?;
+        conn.composite_redirect_window(window, composite::Redirect::AUTOMATIC)
+            .map_err(Self::error)?;
+        let attrs = xproto::ChangeWindowAttributesAux::new().event_mask(xproto::EventMask::STRUCTURE_NOTIFY);
+        conn.change_window_attributes(window, &attrs)
+            .map_err(Self::error)?
+            .check()
+            .map_err(Self::error)?;
+        Self::open(conn, window, true)
+    }
+
+    fn open(conn: RustConnection, window: xproto::Window, redirected: bool) -> io::Result<Self> {
+        let geometry = conn
+            .get_geometry(window)
+            .map_err(Self::error)?
+            .reply()
+            .map_err(Self::error)?;
+        if geometry.depth != 24 && geometry.depth != 32 {
+            return Err(io::Error::new(io::ErrorKind::Other, "unsupported depth"));
+        }
+
+        conn.shm_query_version()
+            .map_err(Self::error)?
+            .reply()
+            .map_err(Self::error)?;
+        conn.damage_query_version(1, 1)
+            .map_err(Self::error)?
+            .reply()
+            .map_err(Self::error)?;
+
+        let damage = conn.generate_id().map_err(Self::error)?;
+        conn.damage_create(damage, window, damage::ReportLevel::RAW_RECTANGLES)
+            .map_err(Self::error)?;
+
+        let mut this = X11Source {
+            conn,
+            window,
+            drawable: window,
+            redirected,
+            w: 0,
+            h: 0,
+            shmseg: 0,
+            shmaddr: ptr::null_mut(),
+            shmsize: 0,
+            damage,
+            rects: Vec::new(),
+            screens: Vec::new(),
+            first: true,
+        };
+        if !redirected {
+            this.screens = Self::query_monitors(&this.conn, window)
+                .map(|monitors| monitors.into_iter().map(|(_, screen)| screen).collect())
+                .unwrap_or_default();
+        }
+        this.resize(geometry.width as usize, geometry.height as usize)?;
+        Ok(this)
+    }
+
+    /// The RandR monitors of the screen, with their names.
+    pub fn monitors(display: Option<&str>) -> io::Result<Vec<(String, capture::Screen)>> {
+        let (conn, screen) = x11rb::connect(display).map_err(Self::error)?;
+        let root = conn.setup().roots[screen].root;
+        Self::query_monitors(&conn, root)
+    }
+
+    fn query_monitors(conn: &RustConnection, root: xproto::Window) -> io::Result<Vec<(String, capture::Screen)>> {
+        conn.randr_query_version(1, 5)
+            .map_err(Self::error)?
+            .reply()
+            .map_err(Self::error)?;
+        let reply = conn
+            .randr_get_monitors(root, true)
+            .map_err(Self::error)?
+            .reply()
+            .map_err(Self::error)?;
+        let mut monitors = Vec::new();
+        for (i, monitor) in reply.monitors.iter().enumerate() {
+            let name = conn
+                .get_atom_name(monitor.name)
+                .map_err(Self::error)?
+                .reply()
+                .map_err(Self::error)?
+                .name;
+            let screen = capture::Screen {
+                id: i as u32,
+                x: cmp::max(monitor.x, 0) as usize,
+                y: cmp::max(monitor.y, 0) as usize,
+                w: monitor.width as usize,
+                h: monitor.height as usize,
+            };
+            monitors.push((String::from_utf8_lossy(&name).into_owned(), screen));
+        }
+        Ok(monitors)
+    }
+
+    fn error<E: error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, err)
+    }
+
+    fn resize(&mut self, w: usize, h: usize) -> io::Result<()> {
+        self.w = w;
+        self.h = h;
+        self.first = true;
+
+        if self.redirected {
+            // the window pixmap is reallocated by the server on resize.
+            if self.drawable != self.window {
+                self.conn.free_pixmap(self.drawable).map_err(Self::error)?;
+            }
+            let pixmap = self.conn.generate_id().map_err(Self::error)?;
+            self.conn
+                .composite_name_window_pixmap(self.window, pixmap)
+                .map_err(Self::error)?
+                .check()
+                .map_err(Self::error)?;
+            self.drawable = pixmap;
+        }
+
+        if 4 * w * h > self.shmsize {
+            self.detach();
+            self.attach(4 * w * h)?;
+        }
+        self.conn.flush().map_err(Self::error)
+    }
+
+    fn attach(&mut self, size: usize) -> io::Result<()> {
+        let shmseg = self.conn.generate_id().map_err(Self::error)?;
+        unsafe {
+            let shmid = libc::shmget(libc::IPC_PRIVATE, size, libc::IPC_CREAT | 0o600);
+            if shmid < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let shmaddr = libc::shmat(shmid, ptr::null(), 0);
+            let attached = self
+                .conn
+                .shm_attach(shmseg, shmid as u32, false)
+                .map_err(Self::error)
+                .and_then(|cookie| cookie.check().map_err(Self::error));
+            // the segment is freed when both of us have detached it.
+            libc::shmctl(shmid, libc::IPC_RMID, ptr::null_mut());
+            if shmaddr as isize == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if let Err(err) = attached {
+                libc::shmdt(shmaddr);
+                return Err(err);
+            }
+            self.shmseg = shmseg;
+            self.shmaddr = shmaddr as *mut u8;
+            self.shmsize = size;
+        }
+        Ok(())
+    }
+
+    fn detach(&mut self) {
+        if !self.shmaddr.is_null() {
+            self.conn.shm_detach(self.shmseg).ok();
+            unsafe { libc::shmdt(self.shmaddr as *const libc::c_void) };
+            self.shmaddr = ptr::null_mut();
+            self.shmsize = 0;
+        }
+    }
+
+    fn poll_events(&mut self) -> io::Result<()> {
+        while let Some(event) = self.conn.poll_for_event().map_err(Self::error)? {
+            match event {
+                Event::DamageNotify(event) => {
+                    let x0 = cmp::max(event.area.x, 0) as usize;
+                    let y0 = cmp::max(event.area.y, 0) as usize;
+                    let x1 = cmp::min(
+                        cmp::max(event.area.x as isize + event.area.width as isize, 0) as usize,
+                        self.w,
+                    );
+                    let y1 = cmp::min(
+                        cmp::max(event.area.y as isize + event.area.height as isize, 0) as usize,
+                        self.h,
+                    );
+                    if x0 < x1 && y0 < y1 {
+                        self.rects.push((x0, y0, x1, y1));
+                    }
+                }
+                Event::ConfigureNotify(event) if event.window == self.window => {
+                    if event.width as usize != self.w || event.height as usize != self.h {
+                        self.resize(event.width as usize, event.height as usize)?;
+                    }
+                }
+                Event::DestroyNotify(event) if event.window == self.window => {
+                    return Err(io::Error::new(io::ErrorKind::Other, "window destroyed"));
+                }
+                _ => (),
+            }
+        }
+        capture::limit_damage(&mut self.rects, Self::MAX_DAMAGE);
+        Ok(())
+    }
+}
+
+impl capture::Source for X11Source {
+    fn width(&self) -> usize {
+        self.w
+    }
+
+    fn height(&self) -> usize {
+        self.h
+    }
+
+    fn screens(&self) -> Vec<capture::Screen> {
+        if self.screens.is_empty() {
+            vec![capture::Screen {
+                id: 0,
+                x: 0,
+                y: 0,
+                w: self.w,
+                h: self.h,
+            }]
+        } else {
+            self.screens.clone()
+        }
+    }
+
+    fn frame(&mut self) -> io::Result<capture::Frame<'_>> {
+        self.rects.clear();
+        self.poll_events()?;
+        if self.first {
+            self.rects.clear();
+            self.rects.push((0, 0, self.w, self.h));
+            self.first = false;
+        }
+        if self.rects.is_empty() {
+            thread::sleep(Self::INTERVAL);
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        self.conn
+            .shm_get_image(
+                self.drawable,
+                0,
+                0,
+                self.w as u16,
+                self.h as u16,
+                !0,
+                xproto::ImageFormat::Z_PIXMAP.into(),
+                self.shmseg,
+                0,
+            )
+            .map_err(Self::error)?
+            .reply()
+            .map_err(Self::error)?;
+
+        Ok(capture::Frame {
+            data: unsafe { slice::from_raw_parts(self.shmaddr as *const u32, self.w * self.h) },
+            width: self.w,
+            height: self.h,
+            stride: self.w,
+            format: capture::PixelFormat::Bgrx,
+            timestamp: time::Instant::now(),
+            damage: capture::Damage::Hints(&self.rects),
+        })
+    }
+}
+
+impl Drop for X11Source {
+    fn drop(&mut self) {
+        self.conn.damage_destroy(self.damage).ok();
+        if self.redirected {
+            if self.drawable != self.window {
+                self.conn.free_pixmap(self.drawable).ok();
+            }
+            self.conn
+                .composite_unredirect_window(self.window, composite::Redirect::AUTOMATIC)
+                .ok();
+        }
+        self.detach();
+        self.conn.flush().ok();
+    }
+}